@@ -0,0 +1,137 @@
+use std::fmt;
+use reqwest::StatusCode;
+
+// error codes defined by the OAI-PMH protocol, section 3.6
+#[derive(Clone, Debug, PartialEq)]
+pub enum OaiErrorCode {
+    BadArgument,
+    BadResumptionToken,
+    BadVerb,
+    CannotDisseminateFormat,
+    IdDoesNotExist,
+    NoRecordsMatch,
+    NoMetadataFormats,
+    NoSetHierarchy,
+    // repositories are creative
+    Other(String),
+}
+
+impl OaiErrorCode {
+    pub fn from_code(code: &str) -> Self {
+        match code {
+            "badArgument" => OaiErrorCode::BadArgument,
+            "badResumptionToken" => OaiErrorCode::BadResumptionToken,
+            "badVerb" => OaiErrorCode::BadVerb,
+            "cannotDisseminateFormat" => OaiErrorCode::CannotDisseminateFormat,
+            "idDoesNotExist" => OaiErrorCode::IdDoesNotExist,
+            "noRecordsMatch" => OaiErrorCode::NoRecordsMatch,
+            "noMetadataFormats" => OaiErrorCode::NoMetadataFormats,
+            "noSetHierarchy" => OaiErrorCode::NoSetHierarchy,
+            other => OaiErrorCode::Other(String::from(other)),
+        }
+    }
+    pub fn as_str(&self) -> &str {
+        match self {
+            OaiErrorCode::BadArgument => "badArgument",
+            OaiErrorCode::BadResumptionToken => "badResumptionToken",
+            OaiErrorCode::BadVerb => "badVerb",
+            OaiErrorCode::CannotDisseminateFormat => "cannotDisseminateFormat",
+            OaiErrorCode::IdDoesNotExist => "idDoesNotExist",
+            OaiErrorCode::NoRecordsMatch => "noRecordsMatch",
+            OaiErrorCode::NoMetadataFormats => "noMetadataFormats",
+            OaiErrorCode::NoSetHierarchy => "noSetHierarchy",
+            OaiErrorCode::Other(code) => code,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum HarvestError {
+    // connection, TLS, reading the body
+    Http(reqwest::Error),
+    // anything but 200 OK, once the retries are exhausted
+    Status(StatusCode),
+    // not well formed, or not the structure we expect
+    Xml(String),
+    // an <error> element in the response
+    Oai { code: OaiErrorCode, message: String },
+    Database(tokio_postgres::Error),
+    // no connection from the pool in time
+    PoolTimeout,
+    // the record is fine XML but we can't make an entry out of it
+    Mapping(String),
+    // stopped on our side
+    Interrupted(String),
+}
+
+impl HarvestError {
+    pub fn missing(element: &str) -> Self {
+        HarvestError::Xml(format!("Missing {element} element"))
+    }
+    pub fn is_oai(&self, expected: OaiErrorCode) -> bool {
+        matches!(self, HarvestError::Oai { code, .. } if *code == expected)
+    }
+    // worth trying again: a deadlock with another site, a lost connection
+    pub fn is_transient(&self) -> bool {
+        match self {
+            HarvestError::PoolTimeout => true,
+            HarvestError::Database(e) => e.is_closed() || e.code().is_some_and(|state| {
+                // transaction rollback and connection exception classes
+                state.code().starts_with("40") || state.code().starts_with("08")
+            }),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for HarvestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HarvestError::Http(e) => write!(f, "HTTP error: {e}"),
+            HarvestError::Status(status) => write!(f, "Status is {}", status.as_u16()),
+            HarvestError::Xml(message) => write!(f, "Invalid XML: {message}"),
+            HarvestError::Oai { code, message } => write!(f, "{}: {message}", code.as_str()),
+            HarvestError::Database(e) => write!(f, "Database error: {e}"),
+            HarvestError::PoolTimeout => write!(f, "Timed out waiting for a database connection"),
+            HarvestError::Mapping(message) => write!(f, "Cannot map record: {message}"),
+            HarvestError::Interrupted(message) => write!(f, "Interrupted: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for HarvestError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HarvestError::Http(e) => Some(e),
+            HarvestError::Database(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for HarvestError {
+    fn from(e: reqwest::Error) -> Self {
+        HarvestError::Http(e)
+    }
+}
+
+impl From<quick_xml::DeError> for HarvestError {
+    fn from(e: quick_xml::DeError) -> Self {
+        HarvestError::Xml(e.to_string())
+    }
+}
+
+impl From<tokio_postgres::Error> for HarvestError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        HarvestError::Database(e)
+    }
+}
+
+impl From<bb8::RunError<tokio_postgres::Error>> for HarvestError {
+    fn from(e: bb8::RunError<tokio_postgres::Error>) -> Self {
+        match e {
+            bb8::RunError::User(e) => HarvestError::Database(e),
+            bb8::RunError::TimedOut => HarvestError::PoolTimeout,
+        }
+    }
+}
@@ -1,53 +1,498 @@
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::mpsc;
 use futures::future::join_all;
-use tokio_postgres::{NoTls, Client};
-use tokio;
+use bb8_postgres::PostgresConnectionManager;
+use tokio_postgres::NoTls;
 use std::env;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use chrono::Utc;
+use clap::{Args, Parser, Subcommand};
+use tracing::Instrument;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+mod error;
 mod oai;
 mod mycorrhiza;
-use oai::pmh::{HarvestParams,HarvestedRecord,SiteType};
+mod probe;
+mod report;
+use oai::pmh::{parse_sets,FieldMapping,Granularity,HarvestParams,HarvestedPage,HarvestedRecord,RetryPolicy,SiteType};
+use error::OaiErrorCode;
+use mycorrhiza::ConnectionPool;
+use report::{RunReport, SiteReport};
+
+// pages downloaded but not yet written, per site
+const PAGE_BUFFER: usize = 4;
+
+// $1 site ids, $2 library ids, $3 site types. An empty array matches everything.
+const SITE_FILTER: &str = r#"
+AND (cardinality($1::INTEGER[]) = 0 OR site.site_id = ANY($1))
+AND (cardinality($2::INTEGER[]) = 0 OR site.library_id = ANY($2))
+AND (cardinality($3::TEXT[]) = 0 OR site.site_type = ANY($3))
+"#;
+
+#[derive(Parser)]
+#[command(version, about = "Harvest OAI-PMH repositories into the collector database")]
+struct Cli {
+    // no subcommand harvests everything, as the cron job expects
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Harvest the sites, all of them unless filtered
+    Harvest {
+        #[command(flatten)]
+        sites: SiteFilter,
+        /// Ignore last_harvested and any saved resumption token, e.g. after a mapping change
+        #[arg(long)]
+        full: bool,
+        /// Download and parse the records without writing anything
+        #[arg(long)]
+        dry_run: bool,
+        /// Write each page with multi-row statements, for big catalogs
+        #[arg(long)]
+        bulk: bool,
+    },
+    /// Check a new OAI-PMH endpoint before adding it to the site table
+    Probe {
+        base_url: String,
+    },
+    /// Map the stored records of the sites again, with the current code and field mappings
+    Reindex {
+        #[command(flatten)]
+        sites: SiteFilter,
+        /// Only recompute the search vectors of the entries
+        #[arg(long)]
+        vectors_only: bool,
+    },
+    /// Show the sites and how their last harvest went
+    ListSites {
+        #[command(flatten)]
+        sites: SiteFilter,
+    },
+    /// Fetch single records with GetRecord and store them again
+    Record {
+        #[command(flatten)]
+        sites: SiteFilter,
+        /// OAI-PMH identifiers, looked up in every selected site
+        #[arg(required = true)]
+        identifiers: Vec<String>,
+        /// Show how the records are mapped without writing them
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Show the records changed since the last harvest, with ListIdentifiers
+    Identifiers {
+        #[command(flatten)]
+        sites: SiteFilter,
+    },
+}
+
+// the options are repeatable, values of the same option are alternatives
+#[derive(Args, Default)]
+struct SiteFilter {
+    /// Only this site
+    #[arg(long = "site", value_name = "SITE_ID")]
+    site_ids: Vec<i32>,
+    /// Only the sites of this library
+    #[arg(long = "library", value_name = "LIBRARY_ID")]
+    library_ids: Vec<i32>,
+    /// Only the sites of this type
+    #[arg(long = "site-type", value_name = "SITE_TYPE", value_parser = parse_site_type)]
+    site_types: Vec<String>,
+}
+
+impl SiteFilter {
+    fn params(&self) -> [&(dyn tokio_postgres::types::ToSql + Sync); 3] {
+        [&self.site_ids, &self.library_ids, &self.site_types]
+    }
+}
+
+fn parse_site_type(name: &str) -> Result<String, String> {
+    match SiteType::from_name(name) {
+        Some(site_type) => Ok(String::from(site_type.name())),
+        None => Err(String::from("expected amusewiki, koha-marc21, koha-unimarc or generic")),
+    }
+}
+
+async fn connect() -> Result<ConnectionPool, Box<dyn std::error::Error>> {
+    let pg_dsn = env::var("DATABASE_URL").expect("DATABASE_URL env variable should be set");
+    // the sites write in parallel, up to this many at once
+    let max_size = match env::var("HARVEST_DB_CONNECTIONS") {
+        Ok(size) => size.parse().expect("HARVEST_DB_CONNECTIONS should be a number"),
+        Err(_) => 8,
+    };
+    let manager = PostgresConnectionManager::new_from_stringlike(&pg_dsn, NoTls)?;
+    let pool = bb8::Pool::builder()
+        .max_size(max_size)
+        .build(manager)
+        .await?;
+    Ok(pool)
+}
+
+// Ask the repository about itself and check the site configuration
+// against it. Failures here are not fatal, the harvest can still work.
+async fn discover(pool: &ConnectionPool, params: &mut HarvestParams, dry_run: bool) {
+    match oai::pmh::identify(&params.base_url, &params.retry).await {
+        Ok(identify) => {
+            params.granularity = identify.granularity();
+            if identify.deleted_record == "no" {
+                tracing::info!("repository does not track deleted records");
+            }
+            if !dry_run
+                && let Err(e) = mycorrhiza::update_site_identify(pool, params, &identify).await {
+                tracing::error!(error = %e, "error saving Identify");
+            }
+        },
+        Err(e) => tracing::warn!(error = %e, "Identify failed"),
+    }
+    match oai::pmh::list_metadata_formats(&params.base_url, None, &params.retry).await {
+        Ok(formats) => {
+            if !formats.iter().any(|f| f.metadata_prefix == params.metadata_prefix) {
+                tracing::warn!(metadata_prefix = params.metadata_prefix,
+                               advertised = ?formats.iter().map(|f| f.metadata_prefix.as_str()).collect::<Vec<&str>>(),
+                               "metadata format not advertised");
+            }
+        },
+        Err(e) => tracing::warn!(error = %e, "ListMetadataFormats failed"),
+    }
+    if !params.sets.is_empty() {
+        match oai::pmh::list_sets(&params.base_url, &params.retry).await {
+            Ok(sets) => {
+                for set in &params.sets {
+                    if !sets.iter().any(|s| &s.spec == set) {
+                        tracing::warn!(set, "no such set in the repository");
+                    }
+                }
+            },
+            Err(e) => tracing::warn!(error = %e, "ListSets failed"),
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let pg_dsn = env::var("DATABASE_URL").expect("DATABASE_URL env variable should be set");
-    let (client, connection) = tokio_postgres::connect(&pg_dsn, NoTls).await?;
-    tokio::spawn(connection);
-    let client = Arc::new(Mutex::new(client));
-    let sql = r#"
-SELECT url, site_type, last_harvested, site_id, library_id
+    // logs go to stderr, reports to stdout
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| format!("{}=info", env!("CARGO_CRATE_NAME")).into()),
+        )
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+        .init();
+    match Cli::parse().command {
+        Some(Command::Harvest { sites, full, dry_run, bulk }) => harvest_sites(&sites, full, dry_run, bulk).await,
+        Some(Command::Probe { base_url }) => {
+            print!("{}", probe::probe(&base_url).await);
+            Ok(())
+        },
+        Some(Command::Reindex { sites, vectors_only }) => reindex_sites(&sites, vectors_only).await,
+        Some(Command::ListSites { sites }) => list_sites(&sites).await,
+        Some(Command::Record { sites, identifiers, dry_run }) => fetch_records(&sites, &identifiers, dry_run).await,
+        Some(Command::Identifiers { sites }) => list_identifiers(&sites).await,
+        None => harvest_sites(&SiteFilter::default(), false, false, false).await,
+    }
+}
+
+// The harvestable sites, with when the interrupted harvest was started
+async fn load_sites(pool: &ConnectionPool, filter: &SiteFilter, overlap: Duration, retry: &RetryPolicy)
+                    -> Result<Vec<(HarvestParams, Option<SystemTime>)>, Box<dyn std::error::Error>> {
+    let sql = format!(r#"
+SELECT url, site_type, last_harvested, site_id, oai_granularity,
+       harvest_resumption_token, harvest_started, harvest_set,
+       COALESCE(oai_metadata_format, 'marc21'), oai_set, field_mapping::TEXT
 FROM site
-WHERE url <> '' AND site_type IN ('amusewiki', 'koha-marc21', 'koha-unimarc')
+WHERE url <> '' AND site_type IN ('amusewiki', 'koha-marc21', 'koha-unimarc', 'generic')
+{SITE_FILTER}
 ORDER BY url
-"#;
-    let rows = client.lock().await.query(sql, &[]).await?;
-    let urls: Vec<HarvestParams> = rows.iter().map(|row| HarvestParams {
-        base_url: row.get(0),
-        site_type: match row.get(1) {
-            "amusewiki" => SiteType::Amusewiki,
-            "koha-marc21" => SiteType::KohaMarc21,
-            "koha-unimarc" => SiteType::KohaUnimarc,
-            _ => panic!("Invalid site_type"),
-        },
-        from: row.get(2),
-        site_id: row.get(3),
-        library_id: row.get(4),
-    }).collect();
+"#);
+    let rows = pool.get().await?.query(&sql, &filter.params()).await?;
+    Ok(rows.iter().filter_map(|row| {
+        let field_mapping = match row.get::<_, Option<&str>>(10).map(FieldMapping::from_json) {
+            Some(Ok(mapping)) => mapping,
+            Some(Err(error)) => {
+                tracing::error!(site_id = row.get::<_, i32>(3), %error, "invalid field_mapping, skipping the site");
+                return None;
+            },
+            None => FieldMapping::default(),
+        };
+        Some((HarvestParams {
+            base_url: row.get(0),
+            site_type: SiteType::from_name(row.get(1)).expect("Invalid site_type"),
+            from: row.get(2),
+            site_id: row.get(3),
+            metadata_prefix: row.get(8),
+            sets: parse_sets(row.get(9)),
+            granularity: Granularity::from_oai(row.get(4)),
+            overlap,
+            retry: retry.clone(),
+            resumption_token: row.get(5),
+            resumption_set: row.get(7),
+            field_mapping: Arc::new(field_mapping),
+        }, row.get(6)))
+    }).collect())
+}
+
+async fn harvest_sites(filter: &SiteFilter, full: bool, dry_run: bool, bulk: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let pool = connect().await?;
+    let overlap = match env::var("HARVEST_OVERLAP_SECONDS") {
+        Ok(secs) => Duration::from_secs(secs.parse().expect("HARVEST_OVERLAP_SECONDS should be a number of seconds")),
+        Err(_) => Duration::from_secs(3600),
+    };
+    let mut retry = RetryPolicy::default();
+    if let Ok(retries) = env::var("HARVEST_MAX_RETRIES") {
+        retry.max_retries = retries.parse().expect("HARVEST_MAX_RETRIES should be a number");
+    }
+    let urls = load_sites(&pool, filter, overlap, &retry).await?;
+    if urls.is_empty() {
+        tracing::warn!("no sites to harvest");
+    }
+    let run_started = Utc::now();
+    let run_timer = Instant::now();
     let mut tasks = Vec::new();
-    for todo in urls {
-        let client = Arc::clone(&client);
+    for (mut todo, interrupted) in urls {
+        if full {
+            todo.from = None;
+            todo.resumption_token = None;
+            todo.resumption_set = None;
+        }
+        let pool = pool.clone();
+        let span = tracing::info_span!("site", site_id = todo.site_id, url = todo.base_url);
         let task = tokio::spawn(async move {
-            let results = oai::pmh::harvest(&todo).await;
-            for res in results {
-                match mycorrhiza::insert_harvested_record(&client, &todo, &res).await {
-                    Ok(return_id) => (),
-                    Err(e) => eprintln!("Error inserting record for {:?}: {:?}", res, e),
+            let timer = Instant::now();
+            let mut report = SiteReport {
+                site_id: todo.site_id,
+                base_url: todo.base_url.clone(),
+                ..SiteReport::default()
+            };
+            let harvest_run_id = if dry_run {
+                None
+            }
+            else {
+                match mycorrhiza::start_harvest_run(&pool, &todo).await {
+                    Ok(id) => Some(id),
+                    Err(e) => {
+                        tracing::error!(error = %e, "error recording the harvest run");
+                        None
+                    },
+                }
+            };
+            discover(&pool, &mut todo, dry_run).await;
+            // a resumed harvest counts from when it was first started
+            let started = match (&todo.resumption_token, interrupted) {
+                (Some(_), Some(interrupted)) => interrupted,
+                _ => SystemTime::now(),
+            };
+            let (sender, mut receiver) = mpsc::channel::<HarvestedPage>(PAGE_BUFFER);
+            let writer = async {
+                while let Some(page) = receiver.recv().await {
+                    if dry_run {
+                        report.count_page(&page);
+                        continue;
+                    }
+                    let token = page.resumption_token.clone();
+                    let set = page.set.clone();
+                    mycorrhiza::store_harvested_page(&pool, &todo, page, &mut report, bulk).await;
+                    if token.is_some() {
+                        report.last_resumption_token = token.clone();
+                    }
+                    // after a failure, keep the last good token so the next run
+                    // picks up the failed records again
+                    if report.failed == 0
+                        && let Some(token) = token
+                        && let Err(e) = mycorrhiza::save_resumption_token(&pool, &todo, set.as_deref(), &token, started).await {
+                        tracing::error!(error = %e, "error saving resumption token");
+                    }
                 }
+            };
+            let (harvested, ()) = tokio::join!(oai::pmh::harvest(&todo, sender), writer);
+            report.completed = harvested.is_ok();
+            report.error = harvested.err().map(|e| e.to_string());
+            // only a complete harvest can move the starting point of the next one
+            if dry_run {
+                tracing::info!("dry run, nothing written");
             }
-        });
+            else if report.completed && report.failed == 0 {
+                if let Err(e) = mycorrhiza::update_last_harvested(&pool, &todo, started).await {
+                    tracing::error!(error = %e, "error updating last_harvested");
+                }
+            } else {
+                tracing::warn!(failed = report.failed, "harvest incomplete, last_harvested not updated");
+            }
+            report.elapsed_secs = timer.elapsed().as_secs_f64();
+            if let Some(id) = harvest_run_id
+                && let Err(e) = mycorrhiza::finish_harvest_run(&pool, id, &report).await {
+                tracing::error!(error = %e, "error recording the harvest run");
+            }
+            report
+        }.instrument(span));
         tasks.push(task);
     }
-    join_all(tasks).await;
+    let mut run = RunReport {
+        started: run_started.to_rfc3339(),
+        dry_run,
+        elapsed_secs: 0.0,
+        sites: Vec::new(),
+    };
+    for task in join_all(tasks).await {
+        match task {
+            Ok(site) => run.sites.push(site),
+            Err(e) => tracing::error!(error = %e, "harvest task failed"),
+        }
+    }
+    run.elapsed_secs = run_timer.elapsed().as_secs_f64();
+    print!("{run}");
+    // e.g. for monitoring
+    if let Ok(path) = env::var("HARVEST_REPORT") {
+        std::fs::write(&path, run.to_json())?;
+    }
+    Ok(())
+}
+
+async fn reindex_sites(filter: &SiteFilter, vectors_only: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let pool = connect().await?;
+    let timer = Instant::now();
+    if vectors_only {
+        let sql = format!("SELECT site_id FROM site WHERE TRUE {SITE_FILTER}");
+        let site_ids: Vec<i32> = pool.get().await?.query(&sql, &filter.params()).await?
+            .iter().map(|row| row.get(0)).collect();
+        let entries = mycorrhiza::reindex_entries(&pool, &site_ids).await?;
+        println!("Reindexed {entries} entries of {} sites in {:.1}s", site_ids.len(), timer.elapsed().as_secs_f64());
+        return Ok(());
+    }
+    for (params, _) in load_sites(&pool, filter, Duration::ZERO, &RetryPolicy::default()).await? {
+        let span = tracing::info_span!("site", site_id = params.site_id, url = params.base_url);
+        let mut report = SiteReport::default();
+        let site_timer = Instant::now();
+        let reindexed = mycorrhiza::reindex_site(&pool, &params, &mut report).instrument(span).await;
+        println!("{}: {} records, {} rebuilt, {} skipped, {} failed, {:.1}s",
+                 params.base_url, report.records, report.updated, report.skipped, report.failed,
+                 site_timer.elapsed().as_secs_f64());
+        if let Err(e) = reindexed {
+            println!("  error: {e}");
+        }
+    }
+    let orphans = mycorrhiza::delete_orphan_entries(&pool).await?;
+    println!("Reindex done in {:.1}s, {orphans} orphan entries removed", timer.elapsed().as_secs_f64());
+    Ok(())
+}
+
+async fn list_sites(filter: &SiteFilter) -> Result<(), Box<dyn std::error::Error>> {
+    let pool = connect().await?;
+    let sql = format!(r#"
+SELECT site.site_id, site.library_id, site.site_type, COALESCE(site.url, ''), site.active,
+       to_char(site.last_harvested, 'YYYY-MM-DD HH24:MI'),
+       run.status, to_char(run.started, 'YYYY-MM-DD HH24:MI')
+FROM site
+LEFT JOIN LATERAL (
+  SELECT status, started FROM harvest_run
+  WHERE harvest_run.site_id = site.site_id
+  ORDER BY started DESC LIMIT 1
+) run ON TRUE
+WHERE TRUE {SITE_FILTER}
+ORDER BY site.site_id
+"#);
+    for row in pool.get().await?.query(&sql, &filter.params()).await? {
+        let active: bool = row.get(4);
+        let last_harvested: Option<String> = row.get(5);
+        let last_run = match (row.get::<_, Option<String>>(6), row.get::<_, Option<String>>(7)) {
+            (Some(status), Some(started)) => format!("{status} {started}"),
+            _ => String::from("never run"),
+        };
+        println!("{:>4} {:>4} {:<12} {:<8} {:<16} {:<26} {}",
+                 row.get::<_, i32>(0),
+                 row.get::<_, i32>(1),
+                 row.get::<_, String>(2),
+                 if active { "active" } else { "inactive" },
+                 last_harvested.as_deref().unwrap_or("never"),
+                 last_run,
+                 row.get::<_, String>(3));
+    }
     Ok(())
 }
 
+// What the record looks like once mapped, to debug it
+fn print_record(res: &HarvestedRecord) {
+    println!("  datestamp: {}", res.datestamp());
+    if res.is_deleted() {
+        println!("  deleted");
+        return;
+    }
+    println!("  identifier: {}", res.identifier());
+    println!("  title: {}", res.title());
+    println!("  subtitle: {}", res.subtitle());
+    for agent in res.agents() {
+        println!("  agent: {} ({})", agent.name, agent.role.name());
+    }
+    println!("  languages: {}", res.languages().join(", "));
+    for subject in res.subjects() {
+        println!("  subject: {subject}");
+    }
+    println!("  years: {:?}", res.edition_years());
+    println!("  publisher: {}", res.publisher());
+    println!("  isbn: {}", res.isbn());
+    println!("  material: {}", res.material_type().map_or("unknown", |t| t.name()));
+    println!("  shelf location: {}", res.shelf_location_code());
+    println!("  checksum: {}", res.checksum());
+}
+
+async fn fetch_records(filter: &SiteFilter, identifiers: &[String], dry_run: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let pool = connect().await?;
+    // someone is waiting at the terminal
+    let retry = RetryPolicy {
+        max_retries: 1,
+        ..RetryPolicy::default()
+    };
+    for (params, _) in load_sites(&pool, filter, Duration::ZERO, &retry).await? {
+        for identifier in identifiers {
+            let res = match oai::pmh::get_record(&params, identifier).await {
+                Ok(res) => res,
+                // not from this site
+                Err(e) if e.is_oai(OaiErrorCode::IdDoesNotExist) => continue,
+                Err(e) => {
+                    println!("{} {identifier}: {e}", params.base_url);
+                    continue;
+                },
+            };
+            println!("{} {identifier}", params.base_url);
+            print_record(&res);
+            if dry_run {
+                continue;
+            }
+            if res.is_deleted() {
+                match mycorrhiza::delete_harvested_record(&pool, &params, &res).await {
+                    Ok(Some(entry_id)) => println!("  deleted entry {entry_id}"),
+                    Ok(None) => println!("  not stored"),
+                    Err(e) => println!("  error: {e}"),
+                }
+            }
+            else {
+                match mycorrhiza::insert_harvested_record(&pool, &params, &res).await {
+                    Ok(stored) => println!("  stored as entry {}", stored.entry_id),
+                    Err(e) => println!("  error: {e}"),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn list_identifiers(filter: &SiteFilter) -> Result<(), Box<dyn std::error::Error>> {
+    let pool = connect().await?;
+    let retry = RetryPolicy {
+        max_retries: 1,
+        ..RetryPolicy::default()
+    };
+    for (params, _) in load_sites(&pool, filter, Duration::ZERO, &retry).await? {
+        match oai::pmh::list_identifiers(&params).await {
+            Ok(headers) => {
+                for header in headers {
+                    println!("{:>4} {} {}{}", params.site_id, header.datestamp(), header.identifier(),
+                             if header.is_deleted() { " deleted" } else { "" });
+                }
+            },
+            Err(e) => println!("{:>4} {}: {e}", params.site_id, params.base_url),
+        }
+    }
+    Ok(())
+}
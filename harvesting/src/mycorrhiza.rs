@@ -1,21 +1,447 @@
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use crate::oai::pmh::{HarvestParams,HarvestedRecord,SiteType};
-use tokio_postgres::{Client};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::time::{Duration, SystemTime};
+use bb8::Pool;
+use bb8_postgres::PostgresConnectionManager;
+use crate::oai::pmh::{HarvestParams,HarvestedPage,HarvestedRecord,Identify,RetryPolicy};
+use crate::error::HarvestError;
+use crate::report::SiteReport;
+use tokio_postgres::{NoTls, Transaction};
 use unicode_normalization::UnicodeNormalization;
 use unicode_categories::UnicodeCategories;
 use chrono::{DateTime, Utc};
-use tokio_postgres;
 
 
+pub type ConnectionPool = Pool<PostgresConnectionManager<NoTls>>;
+
+// for the writes of a single record, much shorter than the HTTP one
+const WRITE_RETRY: RetryPolicy = RetryPolicy {
+    max_retries: 3,
+    initial_delay: Duration::from_millis(200),
+    max_delay: Duration::from_secs(5),
+};
+
 fn strip_diacritics(s: &str) -> String {
     s.nfkd().filter(|c| !c.is_mark_nonspacing()).collect()
 }
 
-pub async fn insert_harvested_record(client: &Arc<Mutex<Client>>,
+// Cut to the size of a VARCHAR column, which counts characters. A long
+// heading should not make the whole record fail.
+fn fit(s: String, max: usize) -> String {
+    match s.char_indices().nth(max) {
+        Some((end, _)) => String::from(&s[..end]),
+        None => s,
+    }
+}
+
+// Run the transaction again while it fails for reasons unrelated to the record
+async fn retry_transient<T, F, Fut>(identifier: &str, mut write: F) -> Result<T, HarvestError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, HarvestError>>,
+{
+    let mut attempt = 0;
+    loop {
+        match write().await {
+            Err(e) if e.is_transient() && attempt < WRITE_RETRY.max_retries => {
+                let delay = WRITE_RETRY.delay(attempt);
+                tracing::warn!(identifier, error = %e, attempt, ?delay, "retrying record");
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            },
+            result => return result,
+        }
+    }
+}
+
+// what insert_harvested_record did
+pub struct StoredRecord {
+    pub entry_id: i32,
+    // a new datasource, not an update
+    pub inserted: bool,
+    pub full_text: bool,
+}
+
+// Write every record of the page, counting what happened in the report.
+// With bulk, the page goes in with a few multi-row statements, and only if
+// that fails record by record. Returns the number of failures.
+#[tracing::instrument(name = "page", skip_all, fields(number = page.number, set = page.set.as_deref()))]
+pub async fn store_harvested_page(pool: &ConnectionPool,
+                                  params: &HarvestParams,
+                                  page: HarvestedPage,
+                                  report: &mut SiteReport,
+                                  bulk: bool)
+                                  -> usize {
+    let mut failed = 0;
+    report.pages += 1;
+    report.records += page.records.len() + page.unparsed;
+    report.skipped += page.unparsed;
+    let mut page = page;
+    match unchanged_records(pool, params, &page.records).await {
+        Ok(unchanged) => {
+            report.unchanged += unchanged.len();
+            page.records.retain(|res| !unchanged.contains(res.oai_pmh_identifier()));
+        },
+        // it only means more work
+        Err(e) => tracing::warn!(error = %e, "cannot look for unchanged records"),
+    }
+    // downloaded once, for the bulk write and for its fallback
+    let mut full_texts: HashMap<String, Option<String>> = HashMap::new();
+    if bulk {
+        for res in &page.records {
+            if !res.is_deleted() && res.has_metadata() && !full_texts.contains_key(res.oai_pmh_identifier()) {
+                full_texts.insert(String::from(res.oai_pmh_identifier()), download_full_text(res).await);
+            }
+        }
+        match write_page(pool, params, &page, &full_texts).await {
+            Ok(stored) => {
+                report.inserted += stored.inserted;
+                report.updated += stored.updated;
+                report.deleted += stored.deleted;
+                report.skipped += stored.skipped;
+                report.full_texts += stored.full_texts;
+                tracing::info!(records = page.records.len(), "page stored in bulk");
+                return 0;
+            },
+            Err(e) => tracing::warn!(error = %e, "bulk write failed, storing record by record"),
+        }
+    }
+    for res in page.records {
+        let identifier = res.oai_pmh_identifier();
+        if res.is_deleted() {
+            match delete_harvested_record(pool, params, &res).await {
+                Ok(Some(entry_id)) => {
+                    tracing::debug!(identifier, entry_id, "deleted");
+                    report.deleted += 1;
+                },
+                // never seen it
+                Ok(None) => (),
+                Err(e) => {
+                    tracing::error!(identifier, error = %e, "error deleting record");
+                    failed += 1;
+                },
+            }
+        }
+        else {
+            let stored = match full_texts.get(identifier) {
+                Some(full_text) => write_record(pool, params, &res, full_text.as_deref()).await,
+                None => insert_harvested_record(pool, params, &res).await,
+            };
+            match stored {
+                Ok(stored) => {
+                    tracing::debug!(identifier, entry_id = stored.entry_id, inserted = stored.inserted, "stored");
+                    if stored.inserted {
+                        report.inserted += 1;
+                    }
+                    else {
+                        report.updated += 1;
+                    }
+                    if stored.full_text {
+                        report.full_texts += 1;
+                    }
+                },
+                // harvesting it again won't help, don't hold the site back
+                Err(e @ HarvestError::Mapping(_)) => {
+                    tracing::warn!(identifier, error = %e, "skipping record");
+                    report.skipped += 1;
+                },
+                Err(e) => {
+                    tracing::error!(identifier, error = %e, record = ?res, "error inserting record");
+                    failed += 1;
+                },
+            }
+        }
+    }
+    report.failed += failed;
+    tracing::info!(failed, "page stored");
+    failed
+}
+
+// The identifiers of the live records stored with the same datestamp and
+// content checksum. Those don't need to be written, nor their full text
+// downloaded again. Without the raw XML, or without the full text they
+// should have, they are written anyway.
+async fn unchanged_records(pool: &ConnectionPool,
+                           params: &HarvestParams,
+                           records: &[HarvestedRecord])
+                           -> Result<HashSet<String>, HarvestError> {
+    let sql = r#"
+SELECT oai_pmh_identifier, datestamp, content_checksum
+FROM datasource
+WHERE site_id = $1 AND oai_pmh_identifier = ANY($2) AND raw_xml IS NOT NULL
+  AND NOT ($3 AND COALESCE(search_text, '') = '')
+"#;
+    let live: Vec<&HarvestedRecord> = records.iter()
+        .filter(|res| !res.is_deleted() && res.has_metadata())
+        .collect();
+    if live.is_empty() {
+        return Ok(HashSet::new());
+    }
+    let identifiers: Vec<&str> = live.iter().map(|res| res.oai_pmh_identifier()).collect();
+    let expects_full_text = live.iter().any(|res| res.expects_full_text());
+    let stored: HashMap<String, (Option<DateTime<Utc>>, Option<String>)> = pool.get().await?
+        .query(sql, &[&params.site_id, &identifiers, &expects_full_text]).await?
+        .iter().map(|row| (row.get(0), (row.get(1), row.get(2)))).collect();
+    Ok(live.iter().filter(|res| {
+        stored.get(res.oai_pmh_identifier()).is_some_and(|(datestamp, checksum)| {
+            *datestamp == res.parsed_datestamp()
+                && checksum.as_deref() == Some(res.content_checksum().as_str())
+        })
+    }).map(|res| String::from(res.oai_pmh_identifier())).collect())
+}
+
+// what write_page did
+#[derive(Default)]
+struct StoredPage {
+    inserted: usize,
+    updated: usize,
+    deleted: usize,
+    skipped: usize,
+    full_texts: usize,
+}
+
+// The whole page in one transaction, one statement per table. The trigger
+// is off, the search vectors are computed once per entry at the end. Keys
+// are sorted so concurrent sites lock the shared rows in the same order.
+async fn write_page(pool: &ConnectionPool,
+                    params: &HarvestParams,
+                    page: &HarvestedPage,
+                    full_texts: &HashMap<String, Option<String>>)
+                    -> Result<StoredPage, HarvestError> {
+    let mut stored = StoredPage::default();
+    let mut deleted = Vec::new();
+    // the upserts can't touch the same row twice: the last copy of a record wins
+    let mut live: BTreeMap<&str, &HarvestedRecord> = BTreeMap::new();
+    for res in &page.records {
+        if res.is_deleted() {
+            deleted.push(res.oai_pmh_identifier());
+        }
+        else if res.has_metadata() {
+            live.insert(res.oai_pmh_identifier(), res);
+        }
+        else {
+            tracing::warn!(identifier = res.oai_pmh_identifier(), "skipping record: no metadata in a live record");
+            stored.skipped += 1;
+        }
+    }
+    let full_texts: Vec<Option<&str>> = live.keys()
+        .map(|identifier| full_texts.get(*identifier).and_then(|full_text| full_text.as_deref()))
+        .collect();
+    let checksums: Vec<String> = live.values().map(|res| res.checksum()).collect();
+    let mut entries = BTreeMap::new();
+    for (res, checksum) in live.values().zip(&checksums) {
+        entries.entry(checksum).or_insert_with(|| {
+            let title = fit(res.title(), 255);
+            let subtitle = fit(res.subtitle(), 255);
+            let search_text = [&title, &subtitle].iter().map(|s| strip_diacritics(s)).collect::<Vec<String>>().join(" ");
+            (title, subtitle, search_text)
+        });
+    }
+
+    let mut c = pool.get().await?;
+    let tx = c.transaction().await?;
+    tx.batch_execute("SET LOCAL collector.skip_search_vector = 'on'").await?;
+    let mut affected: Vec<i32> = Vec::new();
+
+    if !deleted.is_empty() {
+        let sql_datasource = r#"
+DELETE FROM datasource
+WHERE site_id = $1 AND oai_pmh_identifier = ANY($2)
+RETURNING entry_id
+"#;
+        let entry_ids: Vec<i32> = tx.query(sql_datasource, &[&params.site_id, &deleted]).await?
+            .iter().map(|row| row.get(0)).collect();
+        stored.deleted = entry_ids.len();
+        tx.execute(ORPHAN_ENTRIES, &[&entry_ids]).await?;
+        affected.extend(entry_ids);
+    }
+
+    if !live.is_empty() {
+        // The entries of the records, before the page, and whether only
+        // records of the page provide them. Those get their links replaced,
+        // and go if the records moved to other entries.
+        let sql_previous = r#"
+SELECT DISTINCT ds.entry_id,
+       NOT EXISTS (SELECT 1 FROM datasource other
+                   WHERE other.entry_id = ds.entry_id
+                   AND NOT (other.site_id = $1 AND other.oai_pmh_identifier = ANY($2)))
+FROM datasource ds
+WHERE ds.site_id = $1 AND ds.oai_pmh_identifier = ANY($2)
+ORDER BY ds.entry_id
+"#;
+        let identifiers: Vec<&str> = live.keys().copied().collect();
+        let previous: Vec<(i32, bool)> = tx.query(sql_previous, &[&params.site_id, &identifiers]).await?
+            .iter().map(|row| (row.get(0), row.get(1))).collect();
+        let replaced: Vec<i32> = previous.iter().filter(|(_, sole)| *sole).map(|(entry_id, _)| *entry_id).collect();
+        if !replaced.is_empty() {
+            for table in ["entry_agent", "entry_language", "entry_subject"] {
+                tx.execute(&format!("DELETE FROM {table} WHERE entry_id = ANY($1)"), &[&replaced]).await?;
+            }
+        }
+        affected.extend(previous.iter().map(|(entry_id, _)| *entry_id));
+        let sql_entry = r#"
+INSERT INTO entry (title, subtitle, checksum, search_text)
+SELECT * FROM UNNEST($1::TEXT[], $2::TEXT[], $3::TEXT[], $4::TEXT[])
+ON CONFLICT (checksum)
+DO UPDATE SET last_indexed = NOW()
+RETURNING checksum, entry_id
+"#;
+        let entry_ids: HashMap<String, i32> = tx.query(sql_entry, &[
+            &entries.values().map(|e| e.0.as_str()).collect::<Vec<&str>>(),
+            &entries.values().map(|e| e.1.as_str()).collect::<Vec<&str>>(),
+            &entries.keys().map(|checksum| checksum.as_str()).collect::<Vec<&str>>(),
+            &entries.values().map(|e| e.2.as_str()).collect::<Vec<&str>>(),
+        ]).await?.iter().map(|row| (row.get(0), row.get(1))).collect();
+        let record_entry_ids: Vec<i32> = checksums.iter().map(|checksum| entry_ids[checksum]).collect();
+        affected.extend(&record_entry_ids);
+
+        let mut agents = BTreeMap::new();
+        let mut entry_agents = Vec::new();
+        let mut languages = BTreeSet::new();
+        let mut entry_languages = Vec::new();
+        let mut subjects = BTreeMap::new();
+        let mut entry_subjects = Vec::new();
+        for (res, entry_id) in live.values().zip(&record_entry_ids) {
+            for agent in res.agents() {
+                let name = fit(agent.name, 255);
+                agents.entry(name.clone()).or_insert((strip_diacritics(&name), agent.kind.name()));
+                entry_agents.push((*entry_id, name, agent.role.name()));
+            }
+            for lang in res.languages() {
+                languages.insert(lang.clone());
+                entry_languages.push((*entry_id, lang));
+            }
+            for term in res.subjects() {
+                subjects.entry(term.clone()).or_insert(strip_diacritics(&term));
+                entry_subjects.push((*entry_id, term));
+            }
+        }
+
+        if !agents.is_empty() {
+            let sql_agent = r#"
+INSERT INTO agent (full_name, search_text, agent_type)
+SELECT * FROM UNNEST($1::TEXT[], $2::TEXT[], $3::TEXT[])
+ON CONFLICT (full_name)
+DO UPDATE SET agent_type = EXCLUDED.agent_type, last_modified = NOW()
+RETURNING full_name, agent_id
+"#;
+            let sql_bridge = r#"
+INSERT INTO entry_agent (entry_id, agent_id, agent_role)
+SELECT * FROM UNNEST($1::INTEGER[], $2::INTEGER[], $3::TEXT[])
+ON CONFLICT DO NOTHING
+"#;
+            let agent_ids: HashMap<String, i32> = tx.query(sql_agent, &[
+                &agents.keys().map(|name| name.as_str()).collect::<Vec<&str>>(),
+                &agents.values().map(|a| a.0.as_str()).collect::<Vec<&str>>(),
+                &agents.values().map(|a| a.1).collect::<Vec<&str>>(),
+            ]).await?.iter().map(|row| (row.get(0), row.get(1))).collect();
+            tx.execute(sql_bridge, &[
+                &entry_agents.iter().map(|ea| ea.0).collect::<Vec<i32>>(),
+                &entry_agents.iter().map(|ea| agent_ids[&ea.1]).collect::<Vec<i32>>(),
+                &entry_agents.iter().map(|ea| ea.2).collect::<Vec<&str>>(),
+            ]).await?;
+        }
+
+        if !languages.is_empty() {
+            let sql_lang = r#"
+INSERT INTO known_language (language_code)
+SELECT * FROM UNNEST($1::TEXT[])
+ON CONFLICT DO NOTHING
+"#;
+            let sql_bridge = r#"
+INSERT INTO entry_language (entry_id, language_code)
+SELECT * FROM UNNEST($1::INTEGER[], $2::TEXT[])
+ON CONFLICT DO NOTHING
+"#;
+            tx.execute(sql_lang, &[&languages.iter().collect::<Vec<&String>>()]).await?;
+            tx.execute(sql_bridge, &[
+                &entry_languages.iter().map(|el| el.0).collect::<Vec<i32>>(),
+                &entry_languages.iter().map(|el| el.1.as_str()).collect::<Vec<&str>>(),
+            ]).await?;
+        }
+
+        if !subjects.is_empty() {
+            let sql_subject = r#"
+INSERT INTO subject (term, search_text)
+SELECT * FROM UNNEST($1::TEXT[], $2::TEXT[])
+ON CONFLICT (term)
+DO UPDATE SET last_modified = NOW() -- needed so we return the id
+RETURNING term, subject_id
+"#;
+            let sql_bridge = r#"
+INSERT INTO entry_subject (entry_id, subject_id)
+SELECT * FROM UNNEST($1::INTEGER[], $2::INTEGER[])
+ON CONFLICT DO NOTHING
+"#;
+            let subject_ids: HashMap<String, i32> = tx.query(sql_subject, &[
+                &subjects.keys().map(|term| term.as_str()).collect::<Vec<&str>>(),
+                &subjects.values().map(|search_text| search_text.as_str()).collect::<Vec<&str>>(),
+            ]).await?.iter().map(|row| (row.get(0), row.get(1))).collect();
+            tx.execute(sql_bridge, &[
+                &entry_subjects.iter().map(|es| es.0).collect::<Vec<i32>>(),
+                &entry_subjects.iter().map(|es| subject_ids[&es.1]).collect::<Vec<i32>>(),
+            ]).await?;
+        }
+
+        let sql_datasource = format!(r#"
+INSERT INTO datasource ({DATASOURCE_COLUMNS})
+SELECT $1::INTEGER, * FROM UNNEST(
+  $2::TEXT[], $3::INTEGER[], $4::TIMESTAMPTZ[], $5::TEXT[], $6::INTEGER[],
+  $7::INTEGER[], $8::TEXT[], $9::TEXT[], $10::TEXT[], $11::TEXT[],
+  $12::TEXT[], $13::TEXT[], $14::TEXT[], $15::TEXT[], $16::TEXT[],
+  $17::TEXT[], $18::TEXT[], $19::TEXT[], $20::TEXT[]
+)
+{DATASOURCE_CONFLICT}
+RETURNING xmax = 0 AS inserted
+"#);
+        let values: Vec<DatasourceValues> = live.values().zip(&full_texts)
+            .map(|(res, full_text)| DatasourceValues::new(res, *full_text))
+            .collect();
+        let rows = tx.query(&sql_datasource, &[
+            &params.site_id,
+            &values.iter().map(|ds| ds.oai_pmh_identifier.as_str()).collect::<Vec<&str>>(),
+            &record_entry_ids,
+            &values.iter().map(|ds| ds.datestamp).collect::<Vec<DateTime<Utc>>>(),
+            &values.iter().map(|ds| ds.description.as_str()).collect::<Vec<&str>>(),
+            &values.iter().map(|ds| ds.year_edition).collect::<Vec<Option<i32>>>(),
+            &values.iter().map(|ds| ds.year_first_edition).collect::<Vec<Option<i32>>>(),
+            &values.iter().map(|ds| ds.publisher.as_str()).collect::<Vec<&str>>(),
+            &values.iter().map(|ds| ds.isbn.as_str()).collect::<Vec<&str>>(),
+            &values.iter().map(|ds| ds.uri.as_deref()).collect::<Vec<Option<&str>>>(),
+            &values.iter().map(|ds| ds.uri_label.as_deref()).collect::<Vec<Option<&str>>>(),
+            &values.iter().map(|ds| ds.content_type.as_deref()).collect::<Vec<Option<&str>>>(),
+            &values.iter().map(|ds| ds.material_description.as_str()).collect::<Vec<&str>>(),
+            &values.iter().map(|ds| ds.shelf_location_code.as_str()).collect::<Vec<&str>>(),
+            &values.iter().map(|ds| ds.edition_statement.as_str()).collect::<Vec<&str>>(),
+            &values.iter().map(|ds| ds.place_date_of_publication_distribution.as_str()).collect::<Vec<&str>>(),
+            &values.iter().map(|ds| ds.search_text.as_str()).collect::<Vec<&str>>(),
+            &values.iter().map(|ds| ds.material_type).collect::<Vec<Option<&str>>>(),
+            &values.iter().map(|ds| ds.content_checksum.as_deref()).collect::<Vec<Option<&str>>>(),
+            &values.iter().map(|ds| ds.raw_xml.as_deref()).collect::<Vec<Option<&str>>>(),
+        ]).await?;
+        for row in rows {
+            if row.get::<_, bool>(0) {
+                stored.inserted += 1;
+            }
+            else {
+                stored.updated += 1;
+            }
+        }
+        stored.full_texts = full_texts.iter().filter(|full_text| full_text.is_some()).count();
+        tx.execute(ORPHAN_ENTRIES, &[&replaced]).await?;
+    }
+
+    affected.sort_unstable();
+    affected.dedup();
+    let sql_vector = format!("UPDATE entry e SET search_vector = {SEARCH_VECTOR} WHERE e.entry_id = ANY($1)");
+    tx.execute(&sql_vector, &[&affected]).await?;
+    tx.commit().await?;
+    Ok(stored)
+}
+
+pub async fn insert_harvested_record(pool: &ConnectionPool,
                                      params: &HarvestParams,
                                      res: &HarvestedRecord)
-                                     -> Result<i32, Box<dyn std::error::Error>> {
+                                     -> Result<StoredRecord, HarvestError> {
     // println!("{:?}", res.uri());
 //    println!("{:?} {} {} {} {} {}",
 //             params,
@@ -34,6 +460,49 @@ pub async fn insert_harvested_record(client: &Arc<Mutex<Client>>,
 //             res.place_date_of_publication_distribution(),
 //             res.aggregations(),
 //    );
+    // download before taking a connection, it can be slow
+    let full_text = download_full_text(res).await;
+    write_record(pool, params, res, full_text.as_deref()).await
+}
+
+async fn download_full_text(res: &HarvestedRecord) -> Option<String> {
+    res.full_text().await.ok().map(|body| strip_diacritics(&body))
+}
+
+// The record in its own transaction, tried again on transient failures
+async fn write_record(pool: &ConnectionPool,
+                      params: &HarvestParams,
+                      res: &HarvestedRecord,
+                      full_text: Option<&str>)
+                      -> Result<StoredRecord, HarvestError> {
+    if !res.has_metadata() {
+        return Err(HarvestError::Mapping(String::from("no metadata in a live record")));
+    }
+    retry_transient(res.oai_pmh_identifier(), || async move {
+        let mut c = pool.get().await?;
+        let tx = c.transaction().await?;
+        let stored = write_harvested_record(&tx, params, res, full_text).await?;
+        tx.commit().await?;
+        Ok(stored)
+    }).await
+}
+
+// All the writes of a record, so a failure rolls back the lot. The links
+// of an entry provided by this datasource alone are replaced instead of
+// added to, and the entry the datasource used to point to goes if nothing
+// else provides it.
+async fn write_harvested_record(tx: &Transaction<'_>,
+                                params: &HarvestParams,
+                                res: &HarvestedRecord,
+                                full_text: Option<&str>)
+                                -> Result<StoredRecord, HarvestError> {
+    let sql_previous = r#"
+SELECT ds.entry_id,
+       NOT EXISTS (SELECT 1 FROM datasource other
+                   WHERE other.entry_id = ds.entry_id AND other.datasource_id <> ds.datasource_id)
+FROM datasource ds
+WHERE ds.site_id = $1 AND ds.oai_pmh_identifier = $2
+"#;
     let sql = r#"
 INSERT INTO entry (title, subtitle, checksum, search_text)
 VALUES ($1, $2, $3, $4)
@@ -41,71 +510,289 @@ ON CONFLICT (checksum)
 DO UPDATE SET last_indexed = NOW()
 RETURNING entry_id
 "#;
-    let title = res.title();
-    let subtitle = res.subtitle();
+    let sql_vector = format!("UPDATE entry e SET search_vector = {SEARCH_VECTOR} WHERE e.entry_id = $1");
+    let previous = tx.query_opt(sql_previous, &[&params.site_id, &res.oai_pmh_identifier()]).await?
+        .map(|row| (row.get::<_, i32>(0), row.get::<_, bool>(1)));
+    if let Some((entry_id, true)) = previous {
+        for table in ["entry_agent", "entry_language", "entry_subject"] {
+            tx.execute(&format!("DELETE FROM {table} WHERE entry_id = $1"), &[&entry_id]).await?;
+        }
+    }
+    let title = fit(res.title(), 255);
+    let subtitle = fit(res.subtitle(), 255);
     let search_text = [&title, &subtitle].iter().map(|s| strip_diacritics(s)).collect::<Vec<String>>().join(" ");
-    let rows = client.lock().await.query(sql,
+    let row = tx.query_one(sql,
                        &[&title,
                          &subtitle,
                          &res.checksum(),
                          &search_text,
                        ]).await?;
-    match rows.first().map(|row| row.get(0)) {
-        Some(entry_id) => {
-            match insert_agents(client, params, res, entry_id).await {
-                Ok(()) => (),
-                Err(e) => println!("Got {e:?} while inserting agents")
-            };
-            match insert_languages(client, params, res, entry_id).await {
-                Ok(()) => (),
-                Err(e) => println!("Got {e:?} while inserting languages")
-            };
-            match insert_datasource(client, params, res, entry_id).await {
-                Ok(()) => (),
-                Err(e) => println!("Got {e:?} while inserting datasource")
+    let entry_id = row.get(0);
+    insert_agents(tx, res, entry_id).await?;
+    insert_languages(tx, res, entry_id).await?;
+    insert_subjects(tx, res, entry_id).await?;
+    // without a datasource the entry is not linked to the site
+    let inserted = insert_datasource(tx, params, res, entry_id, full_text).await?;
+    if let Some((previous_id, _)) = previous
+        && previous_id != entry_id
+        && tx.execute(ORPHAN_ENTRIES, &[&vec![previous_id]]).await? == 0 {
+        // other sites still have it, but not this datasource
+        tx.execute(&sql_vector, &[&previous_id]).await?;
+    }
+    Ok(StoredRecord {
+        entry_id,
+        inserted,
+        full_text: full_text.is_some(),
+    })
+}
+
+pub async fn update_last_harvested(pool: &ConnectionPool,
+                                   params: &HarvestParams,
+                                   started: SystemTime)
+                                   -> Result<(), HarvestError> {
+    let sql = r#"
+UPDATE site SET
+last_harvested = $1,
+harvest_resumption_token = NULL,
+harvest_set = NULL,
+harvest_started = NULL,
+last_modified = NOW()
+WHERE site_id = $2
+"#;
+    pool.get().await?.execute(sql, &[&started, &params.site_id]).await?;
+    Ok(())
+}
+
+pub async fn update_site_identify(pool: &ConnectionPool,
+                                  params: &HarvestParams,
+                                  identify: &Identify)
+                                  -> Result<(), HarvestError> {
+    let sql = r#"
+UPDATE site SET oai_granularity = $1, oai_deleted_record = $2
+WHERE site_id = $3
+"#;
+    pool.get().await?.execute(sql, &[&identify.granularity,
+                                       &identify.deleted_record,
+                                       &params.site_id]).await?;
+    Ok(())
+}
+
+// A new harvest_run row, to be closed by finish_harvest_run
+pub async fn start_harvest_run(pool: &ConnectionPool,
+                               params: &HarvestParams)
+                               -> Result<i32, HarvestError> {
+    let sql = r#"
+INSERT INTO harvest_run (site_id) VALUES ($1)
+RETURNING harvest_run_id
+"#;
+    let row = pool.get().await?.query_one(sql, &[&params.site_id]).await?;
+    Ok(row.get(0))
+}
+
+pub async fn finish_harvest_run(pool: &ConnectionPool,
+                                harvest_run_id: i32,
+                                report: &SiteReport)
+                                -> Result<(), HarvestError> {
+    let sql = r#"
+UPDATE harvest_run SET
+finished = NOW(),
+status = $1,
+pages = $2,
+records = $3,
+inserted = $4,
+updated = $5,
+deleted = $6,
+skipped = $7,
+failed = $8,
+full_texts = $9,
+unchanged = $10,
+error_message = $11,
+last_resumption_token = $12
+WHERE harvest_run_id = $13
+"#;
+    let counters: Vec<i32> = [report.pages, report.records, report.inserted, report.updated,
+                              report.deleted, report.skipped, report.failed, report.full_texts,
+                              report.unchanged]
+        .iter().map(|n| i32::try_from(*n).unwrap_or(i32::MAX)).collect();
+    pool.get().await?.execute(sql, &[&report.status(),
+                                       &counters[0], &counters[1], &counters[2], &counters[3],
+                                       &counters[4], &counters[5], &counters[6], &counters[7],
+                                       &counters[8],
+                                       &report.error,
+                                       &report.last_resumption_token,
+                                       &harvest_run_id]).await?;
+    Ok(())
+}
+
+// Remember how far an ongoing harvest got, so it can be resumed
+pub async fn save_resumption_token(pool: &ConnectionPool,
+                                   params: &HarvestParams,
+                                   set: Option<&str>,
+                                   token: &str,
+                                   started: SystemTime)
+                                   -> Result<(), HarvestError> {
+    let sql = r#"
+UPDATE site SET harvest_resumption_token = $1, harvest_set = $2, harvest_started = $3
+WHERE site_id = $4
+"#;
+    pool.get().await?.execute(sql, &[&token, &set, &started, &params.site_id]).await?;
+    Ok(())
+}
+
+// Same as the update_search_vector trigger, for the entry aliased as e
+const SEARCH_VECTOR: &str = r#"
+      setweight(to_tsvector(COALESCE(e.search_text, '')), 'A') ||
+      setweight(to_tsvector(COALESCE((SELECT string_agg(a.search_text, ' ')
+                                       FROM agent a
+                                       INNER JOIN entry_agent ea ON a.agent_id = ea.agent_id
+                                       WHERE ea.entry_id = e.entry_id), '')), 'B') ||
+      setweight(to_tsvector(COALESCE((SELECT string_agg(ds.search_text, ' ')
+                                       FROM datasource ds
+                                       WHERE ds.entry_id = e.entry_id), '')), 'C')
+"#;
+
+// Recompute the search vector of all the entries with a datasource in the
+// given sites. Returns the number of entries updated.
+pub async fn reindex_entries(pool: &ConnectionPool,
+                             site_ids: &[i32])
+                             -> Result<u64, HarvestError> {
+    let sql = format!(r#"
+UPDATE entry e SET search_vector = {SEARCH_VECTOR}
+WHERE e.entry_id IN (SELECT entry_id FROM datasource WHERE site_id = ANY($1))
+"#);
+    Ok(pool.get().await?.execute(&sql, &[&site_ids]).await?)
+}
+
+// Drop the given entries, or all of them when NULL, once no site provides
+// them any more.
+const ORPHAN_ENTRIES: &str = r#"
+DELETE FROM entry e
+WHERE ($1::INTEGER[] IS NULL OR e.entry_id = ANY($1))
+AND NOT EXISTS (SELECT 1 FROM datasource ds WHERE ds.entry_id = e.entry_id)
+"#;
+
+// Drop the entries no datasource points to any more, e.g. the ones a
+// record moved away from when the mapping changed its checksum.
+pub async fn delete_orphan_entries(pool: &ConnectionPool) -> Result<u64, HarvestError> {
+    Ok(pool.get().await?.execute(ORPHAN_ENTRIES, &[&None::<Vec<i32>>]).await?)
+}
+
+// Map the records of the site again from datasource.raw_xml, with the
+// current code. Records harvested before raw_xml was saved are left alone.
+pub async fn reindex_site(pool: &ConnectionPool,
+                          params: &HarvestParams,
+                          report: &mut SiteReport)
+                          -> Result<(), HarvestError> {
+    let sql = r#"
+SELECT datasource_id, raw_xml, search_text
+FROM datasource
+WHERE site_id = $1 AND raw_xml IS NOT NULL AND datasource_id > $2
+ORDER BY datasource_id
+LIMIT 500
+"#;
+    let sql_missing = r#"
+SELECT COUNT(*) FROM datasource WHERE site_id = $1 AND raw_xml IS NULL
+"#;
+    let missing: i64 = pool.get().await?.query_one(sql_missing, &[&params.site_id]).await?.get(0);
+    if missing > 0 {
+        tracing::warn!(missing, "records without raw XML, harvest with --full to get them");
+    }
+    let mut last_id = 0;
+    loop {
+        let rows = pool.get().await?.query(sql, &[&params.site_id, &last_id]).await?;
+        let Some(last) = rows.last() else {
+            return Ok(());
+        };
+        last_id = last.get(0);
+        report.pages += 1;
+        for row in rows {
+            report.records += 1;
+            let xml: String = row.get(1);
+            // the full text is not downloaded again, nor found if it failed
+            let full_text: Option<String> = row.get::<_, Option<String>>(2).filter(|text| !text.is_empty());
+            let res = match HarvestedRecord::from_xml(&xml, params) {
+                Ok(res) => res,
+                Err(e) => {
+                    tracing::warn!(datasource_id = row.get::<_, i32>(0), error = %e, "skipping stored record");
+                    report.skipped += 1;
+                    continue;
+                },
             };
-            Ok(entry_id)
-        },
-        None => Err(String::from("No id created").into()),
+            let identifier = res.oai_pmh_identifier();
+            match write_record(pool, params, &res, full_text.as_deref()).await {
+                Ok(stored) => {
+                    tracing::debug!(identifier, entry_id = stored.entry_id, "rebuilt");
+                    report.updated += 1;
+                },
+                Err(e @ HarvestError::Mapping(_)) => {
+                    tracing::warn!(identifier, error = %e, "skipping record");
+                    report.skipped += 1;
+                },
+                Err(e) => {
+                    tracing::error!(identifier, error = %e, "error rebuilding record");
+                    report.failed += 1;
+                },
+            }
+        }
     }
 }
 
-async fn insert_agents(client: &Arc<Mutex<Client>>,
-                       params: &HarvestParams,
+pub async fn delete_harvested_record(pool: &ConnectionPool,
+                                     params: &HarvestParams,
+                                     res: &HarvestedRecord)
+                                     -> Result<Option<i32>, HarvestError> {
+    let sql_datasource = r#"
+DELETE FROM datasource
+WHERE site_id = $1 AND oai_pmh_identifier = $2
+RETURNING entry_id
+"#;
+    let identifier = res.oai_pmh_identifier();
+    retry_transient(identifier, || async move {
+        let mut c = pool.get().await?;
+        let tx = c.transaction().await?;
+        let rows = tx.query(sql_datasource, &[&params.site_id, &identifier]).await?;
+        let deleted = rows.first().map(|row| row.get::<_, i32>(0));
+        if let Some(entry_id) = deleted {
+            tx.execute(ORPHAN_ENTRIES, &[&vec![entry_id]]).await?;
+        }
+        tx.commit().await?;
+        Ok(deleted)
+    }).await
+}
+
+async fn insert_agents(c: &Transaction<'_>,
                        res: &HarvestedRecord,
                        entry_id: i32)
-                       -> Result<(), Box<dyn std::error::Error>> {
-    let c = client.lock().await;
+                       -> Result<(), HarvestError> {
     let sql_agent = r#"
-INSERT INTO agent (full_name, search_text)
-VALUES ($1, $2)
+INSERT INTO agent (full_name, search_text, agent_type)
+VALUES ($1, $2, $3)
 ON CONFLICT (full_name)
-DO UPDATE SET last_modified = NOW() -- needed so we return the id
+DO UPDATE SET agent_type = EXCLUDED.agent_type, last_modified = NOW()
 RETURNING agent_id
 "#;
     let sql_bridge = r#"
-INSERT INTO entry_agent (entry_id, agent_id)
-VALUES ($1, $2)
+INSERT INTO entry_agent (entry_id, agent_id, agent_role)
+VALUES ($1, $2, $3)
 ON CONFLICT DO NOTHING
 "#;
-    for full_name in res.authors() {
-        let row = c.query(sql_agent, &[&full_name, &strip_diacritics(&full_name)]).await?;
+    for agent in res.agents() {
+        let name = fit(agent.name, 255);
+        let row = c.query(sql_agent, &[&name, &strip_diacritics(&name), &agent.kind.name()]).await?;
         match row.first().map(|row| row.get::<_, i32>(0)) {
             Some(agent_id) => {
-                c.query(sql_bridge, &[&entry_id, &agent_id]).await?;
+                c.query(sql_bridge, &[&entry_id, &agent_id, &agent.role.name()]).await?;
             },
-            None => println!("No agent id returned"),
+            None => tracing::warn!(agent = name, "no agent id returned"),
         };
     }
     Ok(())
 }
 
-async fn insert_languages(client: &Arc<Mutex<Client>>,
-                          params: &HarvestParams,
+async fn insert_languages(c: &Transaction<'_>,
                           res: &HarvestedRecord,
                           entry_id: i32)
-                          -> Result<(), Box<dyn std::error::Error>> {
-    let c = client.lock().await;
+                          -> Result<(), HarvestError> {
     let sql_lang = r#"
 INSERT INTO known_language (language_code)
 VALUES ($1)
@@ -123,13 +810,34 @@ ON CONFLICT DO NOTHING
     }
     Ok(())
 }
-async fn insert_datasource(client: &Arc<Mutex<Client>>,
-                           params: &HarvestParams,
-                           res: &HarvestedRecord,
-                           entry_id: i32)
-                           -> Result<(), Box<dyn std::error::Error>> {
-    let sql_datasource = r#"
-INSERT INTO datasource (
+async fn insert_subjects(c: &Transaction<'_>,
+                         res: &HarvestedRecord,
+                         entry_id: i32)
+                         -> Result<(), HarvestError> {
+    let sql_subject = r#"
+INSERT INTO subject (term, search_text)
+VALUES ($1, $2)
+ON CONFLICT (term)
+DO UPDATE SET last_modified = NOW() -- needed so we return the id
+RETURNING subject_id
+"#;
+    let sql_bridge = r#"
+INSERT INTO entry_subject (entry_id, subject_id)
+VALUES ($1, $2)
+ON CONFLICT DO NOTHING
+"#;
+    for term in res.subjects() {
+        let row = c.query(sql_subject, &[&term, &strip_diacritics(&term)]).await?;
+        match row.first().map(|row| row.get::<_, i32>(0)) {
+            Some(subject_id) => {
+                c.query(sql_bridge, &[&entry_id, &subject_id]).await?;
+            },
+            None => tracing::warn!(subject = term, "no subject id returned"),
+        };
+    }
+    Ok(())
+}
+const DATASOURCE_COLUMNS: &str = r#"
   site_id,
   oai_pmh_identifier,
   entry_id,
@@ -146,14 +854,13 @@ INSERT INTO datasource (
   shelf_location_code,
   edition_statement,
   place_date_of_publication_distribution,
-  search_text
-)
-VALUES (
-  $1, $2, $3, $4, $5,
-  $6, $7, $8, $9, $10,
-  $11, $12, $13, $14, $15,
-  $16, $17
-)
+  search_text,
+  material_type,
+  content_checksum,
+  raw_xml
+"#;
+
+const DATASOURCE_CONFLICT: &str = r#"
 ON CONFLICT (site_id, oai_pmh_identifier)
 DO UPDATE SET
 entry_id = EXCLUDED.entry_id,
@@ -171,60 +878,125 @@ shelf_location_code = EXCLUDED.shelf_location_code,
 edition_statement = EXCLUDED.edition_statement,
 place_date_of_publication_distribution = EXCLUDED.place_date_of_publication_distribution,
 search_text = EXCLUDED.search_text,
+material_type = EXCLUDED.material_type,
+content_checksum = EXCLUDED.content_checksum,
+raw_xml = EXCLUDED.raw_xml,
 last_modified = NOW()
-RETURNING datasource_id
-"#;
-    let mut full_text = String::from("");
-    if let Ok(body) = res.full_text().await {
-        full_text = strip_diacritics(&body);
-    };
-    let mut year_edition = None;
-    let mut year_first_edition = None;
-    let years = res.edition_years();
-    if years.len() == 1 {
-        year_edition = years.first();
-    }
-    else if years.len() > 1 {
-        year_first_edition = years.first();
-        year_edition = years.last();
-    }
-    let mut uri = None;
-    let mut uri_label = None;
-    let mut content_type = None;
-    if let Some(uri_struct) = res.uri() {
-        uri = Some(uri_struct.uri);
-        uri_label = Some(uri_struct.uri_label);
-        content_type = Some(uri_struct.content_type);
-    }
-    let datestamp_string = res.datestamp();
-    let datestamp = match datestamp_string.parse::<DateTime<Utc>>() {
-        Ok(t) => t,
-        Err(e) => {
-            eprintln!("Error parsing timestamp: {e}");
+"#;
+
+// the datasource columns of a record, but site_id and entry_id
+struct DatasourceValues {
+    oai_pmh_identifier: String,
+    datestamp: DateTime<Utc>,
+    description: String,
+    year_edition: Option<i32>,
+    year_first_edition: Option<i32>,
+    publisher: String,
+    isbn: String,
+    uri: Option<String>,
+    uri_label: Option<String>,
+    content_type: Option<String>,
+    material_description: String,
+    shelf_location_code: String,
+    edition_statement: String,
+    place_date_of_publication_distribution: String,
+    search_text: String,
+    material_type: Option<&'static str>,
+    // none when the full text failed, so the next harvest tries again
+    content_checksum: Option<String>,
+    raw_xml: Option<String>,
+}
+
+impl DatasourceValues {
+    fn new(res: &HarvestedRecord, full_text: Option<&str>) -> Self {
+        let mut year_edition = None;
+        let mut year_first_edition = None;
+        let years = res.edition_years();
+        if years.len() == 1 {
+            year_edition = years.first().copied();
+        }
+        else if years.len() > 1 {
+            year_first_edition = years.first().copied();
+            year_edition = years.last().copied();
+        }
+        let mut uri = None;
+        let mut uri_label = None;
+        let mut content_type = None;
+        if let Some(uri_struct) = res.uri() {
+            uri = Some(fit(uri_struct.uri, 2048));
+            uri_label = Some(fit(uri_struct.uri_label, 2048));
+            content_type = Some(fit(uri_struct.content_type, 128));
+        }
+        let datestamp = res.parsed_datestamp().unwrap_or_else(|| {
+            tracing::warn!(datestamp = res.datestamp(), "error parsing timestamp");
             Utc::now()
+        });
+        DatasourceValues {
+            oai_pmh_identifier: String::from(res.oai_pmh_identifier()),
+            datestamp,
+            description: res.description(),
+            year_edition,
+            year_first_edition,
+            publisher: res.publisher(),
+            isbn: res.isbn(),
+            uri,
+            uri_label,
+            content_type,
+            material_description: res.material_description(),
+            shelf_location_code: fit(res.shelf_location_code(), 255),
+            edition_statement: res.edition_statement(),
+            place_date_of_publication_distribution: res.place_date_of_publication_distribution(),
+            search_text: String::from(full_text.unwrap_or_default()),
+            material_type: res.material_type().map(|t| t.name()),
+            content_checksum: match (res.expects_full_text(), full_text) {
+                (true, None) => None,
+                _ => Some(res.content_checksum()),
+            },
+            raw_xml: res.raw_xml().map(String::from),
         }
-    };
-    let rows = client.lock().await.query(sql_datasource, &[
+    }
+}
+
+// Returns whether the datasource is new
+async fn insert_datasource(c: &Transaction<'_>,
+                           params: &HarvestParams,
+                           res: &HarvestedRecord,
+                           entry_id: i32,
+                           full_text: Option<&str>)
+                           -> Result<bool, HarvestError> {
+    let sql_datasource = format!(r#"
+INSERT INTO datasource ({DATASOURCE_COLUMNS})
+VALUES (
+  $1, $2, $3, $4, $5,
+  $6, $7, $8, $9, $10,
+  $11, $12, $13, $14, $15,
+  $16, $17, $18, $19, $20
+)
+{DATASOURCE_CONFLICT}
+RETURNING datasource_id, xmax = 0 AS inserted
+"#);
+    let ds = DatasourceValues::new(res, full_text);
+    let row = c.query_one(&sql_datasource, &[
         &params.site_id,
-        &res.oai_pmh_identifier(),
+        &ds.oai_pmh_identifier,
         &entry_id,
-        &datestamp,
-        &res.description(),
-        &year_edition,
-        &year_first_edition,
-        &res.publisher(),
-        &res.isbn(),
-        &uri,
-        &uri_label,
-        &content_type,
-        &res.material_description(),
-        &res.shelf_location_code(),
-        &res.edition_statement(),
-        &res.place_date_of_publication_distribution(),
-        &full_text,
+        &ds.datestamp,
+        &ds.description,
+        &ds.year_edition,
+        &ds.year_first_edition,
+        &ds.publisher,
+        &ds.isbn,
+        &ds.uri,
+        &ds.uri_label,
+        &ds.content_type,
+        &ds.material_description,
+        &ds.shelf_location_code,
+        &ds.edition_statement,
+        &ds.place_date_of_publication_distribution,
+        &ds.search_text,
+        &ds.material_type,
+        &ds.content_checksum,
+        &ds.raw_xml,
     ]).await?;
-    match rows.first().map(|row| row.get::<_, i32>(0)) {
-        Some(datasource_id) => Ok(()),
-        None => Err(String::from("No id created").into()),
-    }
+    Ok(row.get(1))
 }
@@ -1,76 +1,159 @@
-use reqwest;
+use reqwest::{self, StatusCode, header::RETRY_AFTER};
 use serde::Deserialize;
 use quick_xml::de::from_str;
-use chrono::{DateTime, Utc};
+use quick_xml::events::Event;
+use quick_xml::reader::Reader;
+use std::ops::Range;
+use chrono::{DateTime, NaiveDate, Utc};
 use url::Url;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use regex::Regex;
 use std::collections::HashSet;
+use std::sync::Arc;
 use sha2::{Sha256, Digest};
+use tokio::sync::mpsc::Sender;
+use crate::error::{HarvestError, OaiErrorCode};
+use tracing::Instrument;
 
 #[derive(Debug, Deserialize)]
 struct ResponseError {
     #[serde(rename = "@code")]
     code: String,
-    #[serde(rename = "$text")]
+    #[serde(rename = "$text", default)]
     message: String,
 }
 
 
 #[derive(Debug, Deserialize)]
-struct OaiPmhRecordHeader {
+pub struct OaiPmhRecordHeader {
     identifier: String,
     datestamp: String,
     #[serde(rename = "@status")]
     status: Option<String>,
 }
 
+impl OaiPmhRecordHeader {
+    pub fn identifier(&self) -> &str {
+        self.identifier.as_str()
+    }
+    pub fn datestamp(&self) -> &str {
+        self.datestamp.as_str()
+    }
+    pub fn is_deleted(&self) -> bool {
+        self.status.as_deref() == Some("deleted")
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct OaiPmhRecordMetadata {
+    #[serde(rename = "$value")]
+    record: RecordMetadata,
+}
+
+// Dispatched on the root element of the metadata. Elements are matched
+// by local name, so <record>, <marc:record> and <slim:record> are all the
+// same, wherever the namespace is declared.
+#[derive(Debug, Deserialize)]
+enum RecordMetadata {
     #[serde(rename = "record")]
-    record: MarcRecord,
+    Marc(MarcRecord),
+    // some repositories wrap the record in a collection
+    #[serde(rename = "collection")]
+    MarcCollection(MarcCollection),
+    #[serde(rename = "dc")]
+    DublinCore(DcRecord),
 }
 
 #[derive(Debug, Deserialize)]
 struct MarcDataField {
     #[serde(rename = "@tag")]
     tag: String,
-    #[serde(rename = "@ind1")]
-    ind1: String,
-    #[serde(rename = "@ind2")]
-    ind2: String,
-    #[serde(rename = "subfield")]
+    #[serde(rename = "subfield", default)]
     subfields: Vec<MarcSubField>,
 }
 
+impl MarcDataField {
+    // the texts of a subfield, the last one first. None takes nothing.
+    fn values<'a>(&'a self, code: Option<&'a str>) -> impl Iterator<Item = &'a str> {
+        self.subfields.iter().rev()
+            .filter(move |sf| code.is_some_and(|code| sf.code == code))
+            .map(|sf| sf.text.as_str())
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct MarcSubField {
     #[serde(rename = "@code")]
     code: String,
-    #[serde(rename = "$text")]
+    #[serde(rename = "$text", default)]
+    text: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct MarcControlField {
+    #[serde(rename = "@tag")]
+    tag: String,
+    #[serde(rename = "$text", default)]
     text: String,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct MarcRecord {
-    #[serde(rename = "@xmlns")]
-    namespace: String,
-    // ignore this
-    // leader: Option<String>,
-    #[serde(rename = "datafield")]
+    leader: Option<String>,
+    #[serde(rename = "controlfield", default)]
+    controlfields: Vec<MarcControlField>,
+    #[serde(rename = "datafield", default)]
     datafields: Vec<MarcDataField>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct MarcCollection {
+    #[serde(rename = "record", default)]
+    records: Vec<MarcRecord>,
+}
+
+// oai_dc, every element is optional and repeatable
+#[derive(Debug, Deserialize)]
+pub struct DcRecord {
+    #[serde(default)]
+    title: Vec<String>,
+    #[serde(default)]
+    creator: Vec<String>,
+    #[serde(default)]
+    contributor: Vec<String>,
+    #[serde(default)]
+    description: Vec<String>,
+    #[serde(default)]
+    publisher: Vec<String>,
+    #[serde(default)]
+    date: Vec<String>,
+    #[serde(default)]
+    format: Vec<String>,
+    #[serde(default)]
+    identifier: Vec<String>,
+    #[serde(default)]
+    language: Vec<String>,
+    #[serde(default)]
+    subject: Vec<String>,
+    #[serde(rename = "type", default)]
+    dc_type: Vec<String>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct OaiPmhRecord {
     header: OaiPmhRecordHeader,
-    metadata: OaiPmhRecordMetadata,
+    // deleted records come with the header only
+    metadata: Option<OaiPmhRecordMetadata>,
+    // the <record> element as found in the list
+    #[serde(skip)]
+    xml: Option<String>,
 }
 
 #[derive(Debug)]
 pub enum MetadataType {
     Marc21,
     UniMarc,
+    DublinCore,
 }
 
 #[derive(Clone, Debug)]
@@ -78,8 +161,75 @@ pub enum SiteType {
     Amusewiki,
     KohaMarc21,
     KohaUnimarc,
+    // any other repository, MARC21 or oai_dc
+    Generic,
 }
 
+impl SiteType {
+    // as stored in site.site_type
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "amusewiki" => Some(SiteType::Amusewiki),
+            "koha-marc21" => Some(SiteType::KohaMarc21),
+            "koha-unimarc" => Some(SiteType::KohaUnimarc),
+            "generic" => Some(SiteType::Generic),
+            _ => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            SiteType::Amusewiki => "amusewiki",
+            SiteType::KohaMarc21 => "koha-marc21",
+            SiteType::KohaUnimarc => "koha-unimarc",
+            SiteType::Generic => "generic",
+        }
+    }
+}
+
+// the datestamp granularity declared by the repository in Identify
+#[derive(Clone, Debug, PartialEq)]
+pub enum Granularity {
+    Day,
+    Second,
+}
+
+impl Granularity {
+    pub fn from_oai(granularity: &str) -> Self {
+        match granularity {
+            "YYYY-MM-DD" => Granularity::Day,
+            _ => Granularity::Second,
+        }
+    }
+    pub fn format(&self, dt: &DateTime<Utc>) -> String {
+        match self {
+            Granularity::Day => dt.format("%Y-%m-%d").to_string(),
+            Granularity::Second => dt.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+        }
+    }
+}
+
+
+// for the type facet
+#[derive(Debug, PartialEq)]
+pub enum MaterialType {
+    Book,
+    Article,
+    Periodical,
+    Audio,
+    Video,
+}
+
+impl MaterialType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MaterialType::Book => "book",
+            MaterialType::Article => "article",
+            MaterialType::Periodical => "periodical",
+            MaterialType::Audio => "audio",
+            MaterialType::Video => "video",
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct RecordUri {
@@ -88,12 +238,160 @@ pub struct RecordUri {
     pub uri_label: String,
 }
 
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AgentKind {
+    Person,
+    Corporate,
+}
+
+impl AgentKind {
+    // as stored in agent.agent_type
+    pub fn name(&self) -> &'static str {
+        match self {
+            AgentKind::Person => "person",
+            AgentKind::Corporate => "corporate",
+        }
+    }
+}
+
+// what we make of the MARC relators
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AgentRole {
+    Author,
+    Translator,
+    Editor,
+    Contributor,
+}
+
+impl AgentRole {
+    // MARC21 relator terms ($e) and codes ($4), UNIMARC relator codes ($4)
+    pub fn from_relator(relator: &str) -> Option<Self> {
+        let clean = relator.trim().trim_end_matches(['.', ',', ';', ':']).to_lowercase();
+        // id.loc.gov URIs end with the code
+        let clean = clean.rsplit('/').next().unwrap_or("");
+        match clean {
+            "" => None,
+            "aut" | "cre" | "author" | "auth" | "autore" | "auteur" | "autor" | "070" => Some(AgentRole::Author),
+            "trl" | "translator" | "tr" | "trans" | "transl" | "traduttore" | "traduttrice"
+                | "traduction" | "traducteur" | "traductor" | "730" => Some(AgentRole::Translator),
+            "edt" | "edc" | "editor" | "ed" | "eds" | "curatore" | "curatrice" | "a cura di"
+                | "compiler" | "com" | "340" => Some(AgentRole::Editor),
+            _ => Some(AgentRole::Contributor),
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            AgentRole::Author => "author",
+            AgentRole::Translator => "translator",
+            AgentRole::Editor => "editor",
+            AgentRole::Contributor => "contributor",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RecordAgent {
+    pub name: String,
+    pub kind: AgentKind,
+    pub role: AgentRole,
+}
+
+// A tag and the subfields taken from it
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MarcSource {
+    tag: String,
+    subfields: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AgentSource {
+    tag: String,
+    // the parts of the name
+    subfields: Vec<String>,
+    #[serde(default)]
+    relators: Vec<String>,
+    kind: AgentKind,
+    // when the relators say nothing
+    role: AgentRole,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SubjectSource {
+    tag: String,
+    // the heading
+    subfields: Vec<String>,
+    #[serde(default)]
+    subdivisions: Vec<String>,
+}
+
+// The subfield codes of a link. The uri is taken only when it looks like
+// one.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UriSource {
+    tag: String,
+    uri: String,
+    content_type: Option<String>,
+    label: Option<String>,
+}
+
+// The subfield codes of each part of a host item entry, a missing part is
+// not taken. Entries without a name are skipped.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AggregationSource {
+    tag: String,
+    name: String,
+    issue: Option<String>,
+    place_date_publisher: Option<String>,
+    item_identifier: Option<String>,
+}
+
+// Which MARC fields feed each accessor, from site.field_mapping, like
+// {"title": [{"tag": "245", "subfields": ["a", "b"]}]}. A missing key
+// keeps the default for the metadata type, so the empty mapping is the
+// default profile. Dublin Core records are never affected. The links and
+// the host items name their subfields, like
+// {"uri": [{"tag": "952", "uri": "u"}]}.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FieldMapping {
+    identifier: Option<Vec<MarcSource>>,
+    title: Option<Vec<MarcSource>>,
+    subtitle: Option<Vec<MarcSource>>,
+    agents: Option<Vec<AgentSource>>,
+    languages: Option<Vec<MarcSource>>,
+    subjects: Option<Vec<SubjectSource>>,
+    description: Option<Vec<MarcSource>>,
+    dates: Option<Vec<MarcSource>>,
+    publisher: Option<Vec<MarcSource>>,
+    isbn: Option<Vec<MarcSource>>,
+    material_description: Option<Vec<MarcSource>>,
+    shelf_location_code: Option<Vec<MarcSource>>,
+    edition_statement: Option<Vec<MarcSource>>,
+    place_date_of_publication_distribution: Option<Vec<MarcSource>>,
+    uri: Option<Vec<UriSource>>,
+    aggregations: Option<Vec<AggregationSource>>,
+}
+
+impl FieldMapping {
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+}
+
 #[derive(Debug)]
 pub struct HarvestedRecord {
     raw: OaiPmhRecord,
     record_type: MetadataType,
     host: String,
     site_type: SiteType,
+    mapping: Arc<FieldMapping>,
 }
 
 pub fn language_iso_code(lang: &str) -> String {
@@ -317,32 +615,103 @@ pub fn language_iso_code(lang: &str) -> String {
         "tedesco" => "de",
         _ => "unknown",
     };
-    return String::from(mapped);
+    String::from(mapped)
 }
 
 impl HarvestedRecord {
     fn new(record: OaiPmhRecord, params: &HarvestParams) -> Self {
         let base_uri = Url::parse(&params.base_url).expect("url must be valid at this point");
         HarvestedRecord {
-            raw: record,
-            record_type: match params.site_type {
-                SiteType::KohaUnimarc => MetadataType::UniMarc,
-                SiteType::KohaMarc21 => MetadataType::Marc21,
-                SiteType::Amusewiki => MetadataType::Marc21,
+            record_type: match (&record.metadata, &params.site_type) {
+                (Some(OaiPmhRecordMetadata { record: RecordMetadata::DublinCore(_) }), _) => MetadataType::DublinCore,
+                (_, SiteType::KohaUnimarc) => MetadataType::UniMarc,
+                (_, SiteType::KohaMarc21) => MetadataType::Marc21,
+                (_, SiteType::Amusewiki) => MetadataType::Marc21,
+                (_, SiteType::Generic) => MetadataType::Marc21,
             },
             host: match base_uri.host_str() {
                 Some(h) => String::from(h),
                 None => String::from(""),
             },
             site_type: params.site_type.clone(),
+            mapping: params.field_mapping.clone(),
+            raw: record,
+        }
+    }
+    // map the same raw record with different params
+    pub fn remap(self, params: &HarvestParams) -> Self {
+        HarvestedRecord::new(self.raw, params)
+    }
+    // A record as saved in datasource.raw_xml, mapped with the current code
+    pub fn from_xml(xml: &str, params: &HarvestParams) -> Result<Self, HarvestError> {
+        let mut record = from_str::<OaiPmhRecord>(xml)?;
+        record.xml = Some(String::from(xml));
+        Ok(HarvestedRecord::new(record, params))
+    }
+    pub fn raw_xml(&self) -> Option<&str> {
+        self.raw.xml.as_deref()
+    }
+    pub fn has_field(&self, field: &str) -> bool {
+        !self.get_fields(field).is_empty()
+    }
+    // only the first record of a collection is considered, one OAI-PMH
+    // record is one bibliographic record
+    fn marc(&self) -> Option<&MarcRecord> {
+        match &self.raw.metadata {
+            Some(OaiPmhRecordMetadata { record: RecordMetadata::Marc(rec) }) => Some(rec),
+            Some(OaiPmhRecordMetadata { record: RecordMetadata::MarcCollection(coll) }) => coll.records.first(),
+            _ => None,
         }
     }
     fn get_fields(&self, field: &str) -> Vec<&MarcDataField> {
-        let rec = &self.raw.metadata.record;
         let mut out = Vec::new();
-        for df in &rec.datafields {
-            if df.tag == field {
-                out.push(df)
+        if let Some(rec) = self.marc() {
+            for df in &rec.datafields {
+                if df.tag == field {
+                    out.push(df)
+                }
+            }
+        }
+        out
+    }
+    fn leader(&self) -> Option<&str> {
+        self.marc().and_then(|rec| rec.leader.as_deref())
+    }
+    fn control_field(&self, tag: &str) -> Option<&str> {
+        self.marc().and_then(|rec| rec.controlfields.iter().find(|cf| cf.tag == tag))
+            .map(|cf| cf.text.as_str())
+    }
+    // fixed length data elements, by position. None if missing or blank.
+    fn fixed_field(&self, tag: &str, start: usize, end: usize) -> Option<&str> {
+        let value = match tag {
+            "leader" => self.leader(),
+            _ => self.control_field(tag),
+        };
+        value.and_then(|v| v.get(start..end))
+            .filter(|v| !v.trim().is_empty() && !v.chars().all(|c| c == '|'))
+    }
+    fn dc_fields(&self, element: &str) -> Vec<&str> {
+        let mut out = Vec::new();
+        if let Some(OaiPmhRecordMetadata { record: RecordMetadata::DublinCore(dc) }) = &self.raw.metadata {
+            let values = match element {
+                "title" => &dc.title,
+                "creator" => &dc.creator,
+                "contributor" => &dc.contributor,
+                "description" => &dc.description,
+                "publisher" => &dc.publisher,
+                "date" => &dc.date,
+                "format" => &dc.format,
+                "identifier" => &dc.identifier,
+                "language" => &dc.language,
+                "subject" => &dc.subject,
+                "type" => &dc.dc_type,
+                _ => panic!("Unmapped Dublin Core element {element}"),
+            };
+            for value in values {
+                let value = value.trim();
+                if !value.is_empty() {
+                    out.push(value);
+                }
             }
         }
         out
@@ -360,57 +729,298 @@ impl HarvestedRecord {
         }
         out
     }
+    // the site's own sources for an accessor, None to use the default
+    fn mapped<'a, T>(&self, sources: &'a Option<Vec<T>>) -> Option<&'a [T]> {
+        match &self.record_type {
+            MetadataType::Marc21 | MetadataType::UniMarc => sources.as_deref(),
+            MetadataType::DublinCore => None,
+        }
+    }
+    fn mapped_fields(&self, sources: &Option<Vec<MarcSource>>) -> Option<Vec<&str>> {
+        self.mapped(sources).map(|sources| {
+            sources.iter()
+                .flat_map(|source| self.extract_fields(&source.tag, source.subfields.iter().map(String::as_str).collect()))
+                .collect()
+        })
+    }
     // we map these for the db
     pub fn oai_pmh_identifier(&self) -> &str {
-        self.raw.header.identifier.as_str()
+        self.raw.header.identifier()
     }
     pub fn datestamp(&self) -> &str {
-        self.raw.header.datestamp.as_str()
+        self.raw.header.datestamp()
+    }
+    // the day granularity datestamps are taken at midnight UTC
+    pub fn parsed_datestamp(&self) -> Option<DateTime<Utc>> {
+        let datestamp = self.datestamp();
+        datestamp.parse::<DateTime<Utc>>().ok().or_else(|| {
+            NaiveDate::parse_from_str(datestamp, "%Y-%m-%d").ok()
+                .and_then(|day| day.and_hms_opt(0, 0, 0))
+                .map(|dt| dt.and_utc())
+        })
+    }
+    // false for tombstones, and for broken repositories
+    pub fn has_metadata(&self) -> bool {
+        self.raw.metadata.is_some()
+    }
+    // a tombstone: the repository withdrew the record
+    pub fn is_deleted(&self) -> bool {
+        self.raw.header.is_deleted()
     }
     pub fn identifier(&self) -> String {
+        if let Some(fields) = self.mapped_fields(&self.mapping.identifier) {
+            return fields.join(" ");
+        }
         match &self.record_type {
             MetadataType::Marc21 => {
-                self.extract_fields("024", vec!["a"]).join(" ")
+                let ids = self.extract_fields("024", vec!["a"]);
+                match (ids.is_empty(), self.control_field("001")) {
+                    (true, Some(control_number)) => String::from(control_number.trim()),
+                    _ => ids.join(" "),
+                }
             },
             MetadataType::UniMarc => {
                 self.extract_fields("090", vec!["a"]).join(" ")
             },
+            MetadataType::DublinCore => {
+                self.dc_fields("identifier").into_iter()
+                    .filter(|id| !id.starts_with("http"))
+                    .collect::<Vec<&str>>().join(" ")
+            },
         }
     }
     pub fn title(&self) -> String {
+        if let Some(fields) = self.mapped_fields(&self.mapping.title) {
+            return fields.join(" ");
+        }
         match &self.record_type {
             MetadataType::Marc21 => self.extract_fields("245", vec!["a", "b", "c"]).join(" "),
             MetadataType::UniMarc => self.extract_fields("200", vec!["a", "e"]).join(" "),
+            // further titles are usually translations
+            MetadataType::DublinCore => self.dc_fields("title").first().map_or(String::from(""), |t| t.to_string()),
         }
     }
     pub fn subtitle(&self) -> String {
+        if let Some(fields) = self.mapped_fields(&self.mapping.subtitle) {
+            return fields.join(" ");
+        }
         match &self.record_type {
             MetadataType::Marc21 => self.extract_fields("246", vec!["a", "b"]).join(" "),
             MetadataType::UniMarc => String::from(""),
+            MetadataType::DublinCore => String::from(""),
+        }
+    }
+    // Agents from MARC name fields. The parts of the name are joined in
+    // order, the relator terms or codes are normalised into a role.
+    fn marc_agents(&self, tag: &str, kind: AgentKind, default_role: AgentRole,
+                   name_codes: &[&str], relator_codes: &[&str]) -> Vec<RecordAgent> {
+        let mut out = Vec::new();
+        let separator = match kind {
+            AgentKind::Person => ", ",
+            AgentKind::Corporate => ". ",
+        };
+        for df in self.get_fields(tag) {
+            let mut parts = Vec::new();
+            let mut relators = Vec::new();
+            for sf in &df.subfields {
+                if name_codes.contains(&sf.code.as_str()) {
+                    // cataloguing punctuation before the next subfield
+                    let part = match kind {
+                        AgentKind::Person => sf.text.trim().trim_end_matches([',', ';', ':']).trim_end(),
+                        AgentKind::Corporate => sf.text.trim().trim_end_matches([',', ';', ':', '.']).trim_end(),
+                    };
+                    if !part.is_empty() {
+                        parts.push(part);
+                    }
+                }
+                else if relator_codes.contains(&sf.code.as_str()) {
+                    relators.push(sf.text.trim());
+                }
+            }
+            if !parts.is_empty() {
+                out.push(RecordAgent {
+                    name: parts.join(separator),
+                    kind: kind.clone(),
+                    role: relators.iter().find_map(|r| AgentRole::from_relator(r)).unwrap_or(default_role.clone()),
+                });
+            }
         }
+        out
     }
     // multiple
-    pub fn authors(&self) -> Vec<&str> {
+    pub fn agents(&self) -> Vec<RecordAgent> {
+        if let Some(sources) = self.mapped(&self.mapping.agents) {
+            let mut agents = Vec::new();
+            for source in sources {
+                let names: Vec<&str> = source.subfields.iter().map(String::as_str).collect();
+                let relators: Vec<&str> = source.relators.iter().map(String::as_str).collect();
+                agents.extend(self.marc_agents(&source.tag, source.kind.clone(), source.role.clone(), &names, &relators));
+            }
+            return agents;
+        }
         match &self.record_type {
-            MetadataType::Marc21 => self.extract_fields("100", vec!["a"]),
-            MetadataType::UniMarc => self.extract_fields("200", vec!["f"]),
+            // 1XX main entries and 7XX added entries, $e relator term ($j
+            // for meetings) and $4 relator code
+            MetadataType::Marc21 => {
+                let mut agents = self.marc_agents("100", AgentKind::Person, AgentRole::Author, &["a"], &["e", "4"]);
+                agents.extend(self.marc_agents("110", AgentKind::Corporate, AgentRole::Author, &["a", "b"], &["e", "4"]));
+                agents.extend(self.marc_agents("111", AgentKind::Corporate, AgentRole::Author, &["a"], &["j", "4"]));
+                agents.extend(self.marc_agents("700", AgentKind::Person, AgentRole::Contributor, &["a"], &["e", "4"]));
+                agents.extend(self.marc_agents("710", AgentKind::Corporate, AgentRole::Contributor, &["a", "b"], &["e", "4"]));
+                agents.extend(self.marc_agents("711", AgentKind::Corporate, AgentRole::Contributor, &["a"], &["j", "4"]));
+                agents
+            },
+            // UNIMARC 7XX blocks: entry element in $a, the rest of the name
+            // (forename for persons, subdivision for corporate bodies) in $b.
+            // X00 and X01 are primary and alternative responsibility, X02
+            // secondary responsibility.
+            MetadataType::UniMarc => {
+                let mut agents = Vec::new();
+                for (tag, kind, role) in [
+                    ("700", AgentKind::Person, AgentRole::Author),
+                    ("701", AgentKind::Person, AgentRole::Author),
+                    ("702", AgentKind::Person, AgentRole::Contributor),
+                    ("710", AgentKind::Corporate, AgentRole::Author),
+                    ("711", AgentKind::Corporate, AgentRole::Author),
+                    ("712", AgentKind::Corporate, AgentRole::Contributor),
+                ] {
+                    agents.extend(self.marc_agents(tag, kind, role, &["a", "b"], &["4"]));
+                }
+                agents
+            },
+            MetadataType::DublinCore => {
+                let dc_agent = |name: &str, role: AgentRole| RecordAgent {
+                    name: String::from(name),
+                    kind: AgentKind::Person,
+                    role,
+                };
+                let mut agents: Vec<RecordAgent> = self.dc_fields("creator").into_iter()
+                    .map(|name| dc_agent(name, AgentRole::Author)).collect();
+                agents.extend(self.dc_fields("contributor").into_iter()
+                              .map(|name| dc_agent(name, AgentRole::Contributor)));
+                agents
+            },
         }
     }
     // multiple
+    pub fn authors(&self) -> Vec<String> {
+        self.agents().into_iter()
+            .filter(|agent| agent.role == AgentRole::Author)
+            .map(|agent| agent.name)
+            .collect()
+    }
+    // multiple
     pub fn languages(&self) -> Vec<String> {
         let mut langs = Vec::new();
-        match &self.record_type {
-            MetadataType::Marc21 => {
+        match (&self.record_type, self.mapped_fields(&self.mapping.languages)) {
+            (_, Some(fields)) => {
+                langs.extend(fields);
+            },
+            (MetadataType::Marc21, None) => {
                 langs.extend(self.extract_fields("041", vec!["a"]));
                 langs.extend(self.extract_fields("546", vec!["a"]));
             },
-            MetadataType::UniMarc => {
+            (MetadataType::UniMarc, None) => {
                 langs.extend(self.extract_fields("101", vec!["a"]));
             },
+            // often RFC 3066, like en-US
+            (MetadataType::DublinCore, None) => {
+                langs.extend(self.dc_fields("language").into_iter()
+                             .filter_map(|lang| lang.split(['-', '_']).next()));
+            },
+        };
+        // free text like "Text in English" maps to nothing
+        let mut codes: Vec<String> = langs.iter().map(|lang| language_iso_code(lang))
+            .filter(|code| code != "unknown").collect();
+        // 008/35-37 when the record has no usable language fields
+        if codes.is_empty()
+            && let MetadataType::Marc21 = self.record_type
+            && let Some(lang) = self.fixed_field("008", 35, 38)
+            && lang != "und" {
+            codes.push(language_iso_code(lang));
+            codes.retain(|code| code != "unknown");
+        }
+        codes
+    }
+    // Subject headings: the heading parts joined with commas, then the
+    // subdivisions, like "Anarchism -- History -- 19th century"
+    fn marc_subjects(&self, tag: &str, heading_codes: &[&str], subdivision_codes: &[&str]) -> Vec<String> {
+        let mut out = Vec::new();
+        for df in self.get_fields(tag) {
+            let mut heading = Vec::new();
+            let mut subdivisions = Vec::new();
+            for sf in &df.subfields {
+                let text = sf.text.trim().trim_end_matches(['.', ',', ';', ':']).trim_end();
+                if text.is_empty() {
+                    continue;
+                }
+                if heading_codes.contains(&sf.code.as_str()) {
+                    heading.push(text);
+                }
+                else if subdivision_codes.contains(&sf.code.as_str()) {
+                    subdivisions.push(text);
+                }
+            }
+            if !heading.is_empty() {
+                let mut parts = vec![heading.join(", ")];
+                parts.extend(subdivisions.into_iter().map(String::from));
+                out.push(parts.join(" -- "));
+            }
+        }
+        out
+    }
+    // uncontrolled terms, every $a is a subject on its own
+    fn index_terms(&self, tag: &str) -> Vec<String> {
+        self.extract_fields(tag, vec!["a"]).into_iter()
+            .map(|term| term.trim().trim_end_matches(['.', ',', ';', ':']).trim_end())
+            .filter(|term| !term.is_empty())
+            .map(String::from)
+            .collect()
+    }
+    // multiple
+    pub fn subjects(&self) -> Vec<String> {
+        let mut subjects = Vec::new();
+        match (&self.record_type, self.mapped(&self.mapping.subjects)) {
+            (_, Some(sources)) => {
+                for source in sources {
+                    let heading: Vec<&str> = source.subfields.iter().map(String::as_str).collect();
+                    let subdivisions: Vec<&str> = source.subdivisions.iter().map(String::as_str).collect();
+                    subjects.extend(self.marc_subjects(&source.tag, &heading, &subdivisions));
+                }
+            },
+            // amusewiki puts its topics in 653
+            (MetadataType::Marc21, None) => {
+                let subdivisions = ["v", "x", "y", "z"];
+                subjects.extend(self.marc_subjects("650", &["a", "b"], &subdivisions));
+                subjects.extend(self.marc_subjects("651", &["a"], &subdivisions));
+                subjects.extend(self.index_terms("653"));
+                subjects.extend(self.marc_subjects("655", &["a"], &subdivisions));
+            },
+            // 600 to 608 are controlled, 610 uncontrolled
+            (MetadataType::UniMarc, None) => {
+                let subdivisions = ["j", "x", "y", "z"];
+                subjects.extend(self.marc_subjects("600", &["a", "b", "f"], &subdivisions));
+                subjects.extend(self.marc_subjects("601", &["a", "b"], &subdivisions));
+                subjects.extend(self.marc_subjects("602", &["a", "f"], &subdivisions));
+                subjects.extend(self.marc_subjects("604", &["a", "t"], &subdivisions));
+                subjects.extend(self.marc_subjects("605", &["a"], &subdivisions));
+                subjects.extend(self.marc_subjects("606", &["a"], &subdivisions));
+                subjects.extend(self.marc_subjects("607", &["a"], &subdivisions));
+                subjects.extend(self.marc_subjects("608", &["a"], &subdivisions));
+                subjects.extend(self.index_terms("610"));
+            },
+            (MetadataType::DublinCore, None) => {
+                subjects.extend(self.dc_fields("subject").into_iter().map(String::from));
+            },
         };
-        langs.iter().map(|lang| language_iso_code(lang)).collect()
+        let mut seen = HashSet::new();
+        subjects.retain(|subject| seen.insert(subject.clone()));
+        subjects
     }
     pub fn description(&self) -> String {
+        if let Some(fields) = self.mapped_fields(&self.mapping.description) {
+            return fields.join(" ");
+        }
         match &self.record_type {
             MetadataType::Marc21 => {
                 self.extract_fields("520", vec!["a"]).join(" ")
@@ -420,9 +1030,15 @@ impl HarvestedRecord {
                 descs.extend(self.extract_fields("330", vec!["a"]));
                 descs.join(" ")
             },
+            MetadataType::DublinCore => {
+                self.dc_fields("description").join(" ")
+            },
         }
     }
     fn dates(&self) -> Vec<&str> {
+        if let Some(fields) = self.mapped_fields(&self.mapping.dates) {
+            return fields;
+        }
         match &self.record_type {
             MetadataType::Marc21 => {
                 let mut dates = self.extract_fields("264", vec!["c"]);
@@ -433,18 +1049,68 @@ impl HarvestedRecord {
             MetadataType::UniMarc => {
                 self.extract_fields("210", vec!["d"])
             },
+            MetadataType::DublinCore => {
+                self.dc_fields("date")
+            },
         }
     }
+    // MARC21 008/07-14 and UNIMARC 100$a/09-16, two dates
+    fn coded_dates(&self) -> Vec<&str> {
+        let coded = match &self.record_type {
+            MetadataType::Marc21 => self.control_field("008").map_or(Vec::new(), |cf| vec![cf.get(7..15)]),
+            MetadataType::UniMarc => self.extract_fields("100", vec!["a"]).into_iter().map(|a| a.get(9..17)).collect(),
+            MetadataType::DublinCore => Vec::new(),
+        };
+        coded.into_iter().flatten()
+            .flat_map(|dates| [dates.get(0..4), dates.get(4..8)])
+            .flatten()
+            // 9999 is an ongoing publication
+            .filter(|year| year.chars().all(|c| c.is_ascii_digit()) && *year != "9999")
+            .collect()
+    }
     pub fn edition_years(&self) -> Vec<i32> {
         let re = Regex::new(r"\b\d{4}\b").unwrap();
-        let unique: HashSet<i32> = re.captures_iter(self.dates().join(" ").as_str())
-            .filter_map(|c| c.get(0).map(|year| year.as_str().parse::<i32>().ok()).flatten())
+        let mut unique: HashSet<i32> = re.captures_iter(self.dates().join(" ").as_str())
+            .filter_map(|c| c.get(0).and_then(|year| year.as_str().parse::<i32>().ok()))
             .collect();
+        if unique.is_empty() {
+            unique.extend(self.coded_dates().into_iter().filter_map(|year| year.parse::<i32>().ok()));
+        }
         let mut years: Vec<i32> = unique.into_iter().collect();
         years.sort_unstable();
         years
     }
+    // From the leader: type of record (06) and bibliographic level (07),
+    // which have mostly the same values in MARC21 and UNIMARC
+    pub fn material_type(&self) -> Option<MaterialType> {
+        match &self.record_type {
+            MetadataType::Marc21 | MetadataType::UniMarc => {
+                let record_type = self.fixed_field("leader", 6, 7)?;
+                let level = self.fixed_field("leader", 7, 8).unwrap_or("m");
+                match (record_type, level) {
+                    ("i" | "j", _) => Some(MaterialType::Audio),
+                    ("g", _) => Some(MaterialType::Video),
+                    ("a" | "b" | "t", "a" | "b") => Some(MaterialType::Article),
+                    ("a" | "b" | "t", "s") => Some(MaterialType::Periodical),
+                    ("a" | "b" | "t", "m" | "c" | "d") => Some(MaterialType::Book),
+                    _ => None,
+                }
+            },
+            // DCMI type vocabulary
+            MetadataType::DublinCore => {
+                self.dc_fields("type").into_iter().find_map(|t| match t.to_lowercase().as_str() {
+                    "sound" => Some(MaterialType::Audio),
+                    "movingimage" => Some(MaterialType::Video),
+                    "text" => Some(MaterialType::Book),
+                    _ => None,
+                })
+            },
+        }
+    }
     pub fn publisher(&self) -> String {
+        if let Some(fields) = self.mapped_fields(&self.mapping.publisher) {
+            return fields.join(" ");
+        }
         match &self.record_type {
             MetadataType::Marc21 => {
                 let mut publishers = self.extract_fields("260",  vec!["b"]);
@@ -454,9 +1120,15 @@ impl HarvestedRecord {
             MetadataType::UniMarc => {
                 self.extract_fields("210", vec!["c"]).join(" ")
             },
+            MetadataType::DublinCore => {
+                self.dc_fields("publisher").join(" ")
+            },
         }
     }
     pub fn isbn(&self) -> String {
+        if let Some(fields) = self.mapped_fields(&self.mapping.isbn) {
+            return fields.join(" ");
+        }
         match &self.record_type {
             MetadataType::Marc21 => {
                 self.extract_fields("020", vec!["a"]).join(" ")
@@ -464,62 +1136,82 @@ impl HarvestedRecord {
             MetadataType::UniMarc => {
                 self.extract_fields("010", vec!["a"]).join(" ")
             },
+            // like urn:isbn:978... or ISBN 978...
+            MetadataType::DublinCore => {
+                let re = Regex::new(r"(?i)^(urn:)?isbn[:\s]*").unwrap();
+                self.dc_fields("identifier").into_iter()
+                    .filter(|id| re.is_match(id))
+                    .map(|id| re.replace(id, "").to_string())
+                    .collect::<Vec<String>>().join(" ")
+            },
+        }
+    }
+    // the links of a field with their origin check, in record order
+    fn marc_uris(&self, source: &UriSource) -> Vec<(RecordUri, bool)> {
+        let re = Regex::new(r"https?://").unwrap();
+        let mut out = Vec::new();
+        for field in self.get_fields(&source.tag) {
+            let last = |code: &Option<String>| String::from(field.values(code.as_deref()).next().unwrap_or(""));
+            if let Some(uri) = field.values(Some(&source.uri)).find(|uri| re.is_match(uri)) {
+                out.push((RecordUri {
+                    uri: String::from(uri),
+                    content_type: last(&source.content_type),
+                    uri_label: last(&source.label),
+                }, uri.contains(&self.host)));
+            }
         }
+        out
     }
     pub fn uri(&self) -> Option<RecordUri> {
+        if let Some(sources) = self.mapped(&self.mapping.uri) {
+            // in the order of the sources, but the origin wins
+            let uris: Vec<(RecordUri, bool)> = sources.iter()
+                .flat_map(|source| self.marc_uris(source)).collect();
+            let origin = uris.iter().position(|(_, same_host)| *same_host).unwrap_or(0);
+            return uris.into_iter().nth(origin).map(|(uri, _)| uri);
+        }
         match &self.record_type {
             MetadataType::Marc21 => {
-                let re = Regex::new(r"https?://").unwrap();
-                let mut found_uri = None;
-                for uri in self.get_fields("856") {
-                    let mut same_host = false;
-                    let mut found = false;
-                    let mut uri_str = "";
-                    let mut content_type = "";
-                    let mut label = "";
-                    for sf in &uri.subfields {
-                        if &sf.code == "u" {
-                            if re.is_match(&sf.text) {
-                                // println!("{} found!", sf.text);
-                                same_host = sf.text.contains(&self.host);
-                                uri_str = &sf.text;
-                                found = true;
-                            }
-                        } else if &sf.code == "q" {
-                            content_type = &sf.text;
-                        } else if &sf.code == "y" {
-                            label = &sf.text;
-                        }
-                    }
-                    if found {
-                        let uri_struct = RecordUri {
-                            uri: String::from(uri_str),
-                            content_type: String::from(content_type),
-                            uri_label: String::from(label),
-                        };
-                        found_uri = Some(uri_struct);
-                        // if we have an uri matching the origin, stop here
-                        if same_host {
-                            break
-                        }
-                    }
-                }
+                let source = UriSource {
+                    tag: String::from("856"),
+                    uri: String::from("u"),
+                    content_type: Some(String::from("q")),
+                    label: Some(String::from("y")),
+                };
+                let mut uris = self.marc_uris(&source);
+                // if we have an uri matching the origin take it, else the last one
+                let found = match uris.iter().position(|(_, same_host)| *same_host) {
+                    Some(i) => Some(uris.swap_remove(i).0),
+                    None => uris.pop().map(|(uri, _)| uri),
+                };
                 // try the koha uri if nothing was found
-                if let None = found_uri {
-                    if let Some(koha_uri) = self.extract_fields("952", vec!["u"]).first() {
-                        found_uri = Some(RecordUri {
-                            uri: koha_uri.to_string(),
-                            content_type: String::from(""),
-                            uri_label: String::from(""),
-                        });
-                    }
-                }
-                found_uri
+                found.or_else(|| {
+                    self.extract_fields("952", vec!["u"]).first().map(|koha_uri| RecordUri {
+                        uri: koha_uri.to_string(),
+                        content_type: String::from(""),
+                        uri_label: String::from(""),
+                    })
+                })
+            },
+            MetadataType::UniMarc => None,
+            MetadataType::DublinCore => {
+                let uris: Vec<&str> = self.dc_fields("identifier").into_iter()
+                    .filter(|id| id.starts_with("http://") || id.starts_with("https://"))
+                    .collect();
+                // prefer an uri matching the origin
+                uris.iter().find(|uri| uri.contains(&self.host)).or(uris.first())
+                    .map(|uri| RecordUri {
+                        uri: uri.to_string(),
+                        content_type: String::from(""),
+                        uri_label: String::from(""),
+                    })
             },
-            MetadataType::UniMarc => None
         }
     }
     pub fn material_description(&self) -> String {
+        if let Some(fields) = self.mapped_fields(&self.mapping.material_description) {
+            return fields.join(" ");
+        }
         match &self.record_type {
             MetadataType::Marc21 => {
                 self.extract_fields("300", vec!["a", "b", "c", "e"]).join(" ")
@@ -527,9 +1219,15 @@ impl HarvestedRecord {
             MetadataType::UniMarc => {
                 self.extract_fields("215", vec!["a", "c", "d", "e"]).join(" ")
             },
+            MetadataType::DublinCore => {
+                self.dc_fields("format").join(" ")
+            },
         }
     }
     pub fn shelf_location_code(&self) -> String {
+        if let Some(fields) = self.mapped_fields(&self.mapping.shelf_location_code) {
+            return fields.join(" ");
+        }
         match &self.record_type {
             MetadataType::Marc21 => {
                 let mut locs = self.extract_fields("952", vec!["o"]);
@@ -541,9 +1239,13 @@ impl HarvestedRecord {
                 locs.extend(self.extract_fields("676", vec!["a"]));
                 locs.join(" ")
             },
+            MetadataType::DublinCore => String::from(""),
         }
     }
     pub fn edition_statement(&self) -> String {
+        if let Some(fields) = self.mapped_fields(&self.mapping.edition_statement) {
+            return fields.join(" ");
+        }
         match &self.record_type {
             MetadataType::Marc21 => {
                 self.extract_fields("250", vec!["a"]).join(" ")
@@ -551,9 +1253,13 @@ impl HarvestedRecord {
             MetadataType::UniMarc => {
                 self.extract_fields("255", vec!["a", "v"]).join(" ")
             },
+            MetadataType::DublinCore => String::from(""),
         }
     }
     pub fn place_date_of_publication_distribution(&self) -> String {
+        if let Some(fields) = self.mapped_fields(&self.mapping.place_date_of_publication_distribution) {
+            return fields.join(" ");
+        }
         match &self.record_type {
             MetadataType::Marc21 => {
                 let mut places = self.extract_fields("260", vec!["a", "c"]);
@@ -563,50 +1269,44 @@ impl HarvestedRecord {
             MetadataType::UniMarc => {
                 self.extract_fields("210", vec!["a", "d"]).join(" ")
             },
+            MetadataType::DublinCore => String::from(""),
         }
     }
-    pub fn aggregations(&self) -> Vec<RecordAggregation> {
+    fn marc_aggregations(&self, source: &AggregationSource) -> Vec<RecordAggregation> {
         let mut out = Vec::<RecordAggregation>::new();
+        for aggregation_field in self.get_fields(&source.tag) {
+            // the last value of a part, as it was
+            let part = |code: Option<&str>| aggregation_field.values(code).next().map(String::from);
+            let agg = RecordAggregation {
+                name: part(Some(&source.name)),
+                issue: part(source.issue.as_deref()),
+                place_date_publisher: part(source.place_date_publisher.as_deref()),
+                item_identifier: part(source.item_identifier.as_deref()),
+                host: self.host.clone(),
+            };
+            if agg.name.is_some() {
+                out.push(agg);
+            }
+        }
+        out
+    }
+    pub fn aggregations(&self) -> Vec<RecordAggregation> {
+        if let Some(sources) = self.mapped(&self.mapping.aggregations) {
+            return sources.iter().flat_map(|source| self.marc_aggregations(source)).collect();
+        }
         match &self.record_type {
             MetadataType::Marc21 => {
-                for aggregation_field in self.get_fields("773") {
-                    let mut agg = RecordAggregation {
-                        name: None,
-                        issue: None,
-                        isbn: None,
-                        order: None,
-                        place_date_publisher: None,
-                        item_identifier: None,
-                        linkage: None,
-                        host: self.host.clone(),
-                    };
-                    for sf in &aggregation_field.subfields {
-                        let text = String::from(&sf.text);
-                        match sf.code.as_str() {
-                            "t" => { agg.name = Some(text) }
-                            "g" => { agg.issue = Some(text) },
-                            "z" => { agg.isbn = Some(text) },
-                            "q" => {
-                                match text.parse::<i32>() {
-                                    Ok(i) => { agg.order = Some(i) },
-                                    Err(_) => (),
-                                }
-                            },
-                            "d" => { agg.place_date_publisher = Some(text) },
-                            "o" => { agg.item_identifier = Some(text) },
-                            "6" => { agg.linkage = Some(text) },
-                            _ => (),
-                        };
-                    }
-                    if let Some(_) = agg.name {
-                        // println!("Aggregation: {}", agg.identifier());
-                        out.push(agg);
-                    }
-                }
+                self.marc_aggregations(&AggregationSource {
+                    tag: String::from("773"),
+                    name: String::from("t"),
+                    issue: Some(String::from("g")),
+                    place_date_publisher: Some(String::from("d")),
+                    item_identifier: Some(String::from("o")),
+                })
             },
-            MetadataType::UniMarc => (),
-        };
-        out
+            MetadataType::UniMarc => Vec::new(),
+            MetadataType::DublinCore => Vec::new(),
+        }
     }
     pub fn checksum(&self) -> String {
         let mut hasher = Sha256::new();
@@ -624,22 +1324,59 @@ impl HarvestedRecord {
         hasher.update(self.title());
         format!("{:x}", hasher.finalize())
     }
-    pub async fn full_text(&self) -> Result<String, Box<dyn std::error::Error>> {
+    // Everything we store from the record, to tell if it changed since the
+    // last harvest. Changes in the mapping change it as well.
+    pub fn content_checksum(&self) -> String {
+        let mut fields = vec![self.checksum(), self.title(), self.subtitle()];
+        for agent in self.agents() {
+            fields.push(format!("{} {} {}", agent.name, agent.role.name(), agent.kind.name()));
+        }
+        fields.extend(self.languages());
+        fields.extend(self.subjects());
+        fields.extend(self.edition_years().iter().map(|year| year.to_string()));
+        if let Some(uri) = self.uri() {
+            fields.extend([uri.uri, uri.uri_label, uri.content_type]);
+        }
+        fields.extend([
+            self.description(),
+            self.publisher(),
+            self.isbn(),
+            self.material_description(),
+            self.shelf_location_code(),
+            self.edition_statement(),
+            self.place_date_of_publication_distribution(),
+            String::from(self.material_type().map_or("", |t| t.name())),
+        ]);
+        let mut hasher = Sha256::new();
+        for field in fields {
+            hasher.update(field);
+            hasher.update([0]);
+        }
+        format!("{:x}", hasher.finalize())
+    }
+    // amusewiki texts are indexed with the record
+    pub fn expects_full_text(&self) -> bool {
+        matches!(self.site_type, SiteType::Amusewiki)
+    }
+    pub async fn full_text(&self) -> Result<String, HarvestError> {
         match self.site_type {
             SiteType::Amusewiki => {
                 match self.uri() {
                     Some(uri) => {
                         let muse = format!("{}.muse", uri.uri);
-                        let body = reqwest::get(&muse).await?.text().await?;
-                        Ok(body)
+                        let res = reqwest::get(&muse).await?;
+                        if res.status() != StatusCode::OK {
+                            return Err(HarvestError::Status(res.status()));
+                        }
+                        Ok(res.text().await?)
                     },
                     None => {
-                        Err(format!("No uri found").into())
+                        Err(HarvestError::Mapping(String::from("No uri found")))
                     },
                 }
             }
             _ => {
-                Err(format!("Not a site type with full text").into())
+                Err(HarvestError::Mapping(String::from("Not a site type with full text")))
             },
         }
     }
@@ -649,31 +1386,30 @@ impl HarvestedRecord {
 pub struct RecordAggregation {
     name: Option<String>,
     issue: Option<String>,
-    isbn: Option<String>,
-    order: Option<i32>,
     place_date_publisher: Option<String>,
     item_identifier: Option<String>,
-    linkage: Option<String>,
     host: String,
 }
 
 impl RecordAggregation {
     pub fn name(&self) -> &str {
         match &self.name {
-            Some(aggregation_name) => &aggregation_name,
+            Some(aggregation_name) => aggregation_name,
             None => panic!("The name method cannot be called without the name set")
         }
     }
+    // not stored yet, aggregations only go into the entry checksum
+    #[allow(dead_code)]
     pub fn identifier(&self) -> String {
         let mut identifier = vec!["aggregation", &self.host];
         match &self.item_identifier {
             Some(item_identifier) => {
-                identifier.push(&item_identifier)
+                identifier.push(item_identifier)
             },
             None => {
                 identifier.push(self.name());
                 if let Some(issue_number) = &self.issue {
-                    identifier.push(&issue_number)
+                    identifier.push(issue_number)
                 }
             }
         }
@@ -683,63 +1419,304 @@ impl RecordAggregation {
         let mut full_name = Vec::new();
         full_name.push(self.name());
         if let Some(issue_number) = &self.issue {
-            full_name.push(&issue_number)
+            full_name.push(issue_number)
         }
         if let Some(place_date_publisher) = &self.place_date_publisher {
-            full_name.push(&place_date_publisher)
+            full_name.push(place_date_publisher)
         }
         full_name.join(" ")
     }
+    #[allow(dead_code)]
     pub fn checksum(&self) -> String {
         format!("{:x}", Sha256::digest(self.full_aggregation_name()))
     }
 }
 
+// The records are cut out of the response and parsed one by one, see
+// parse_response
 #[derive(Debug, Deserialize)]
 struct ListRecords {
     #[serde(rename = "resumptionToken")]
     resumption_token: Option<String>,
-    #[serde(rename = "record")]
+    #[serde(skip)]
     records: Vec<OaiPmhRecord>,
+    #[serde(skip)]
+    unparsed: Vec<UnparsedRecord>,
+}
+
+impl ListRecords {
+    fn log_unparsed(&self, url: &Url) {
+        for unparsed in &self.unparsed {
+            tracing::warn!(%url, identifier = unparsed.identifier.as_deref(), error = %unparsed.error,
+                           xml = %unparsed.xml, "skipping malformed record");
+        }
+    }
+}
+
+// a record we could not make sense of, kept for the logs
+#[derive(Debug)]
+pub struct UnparsedRecord {
+    pub identifier: Option<String>,
+    pub error: String,
+    pub xml: String,
+}
+
+impl UnparsedRecord {
+    fn new(xml: &str, error: String) -> Self {
+        // the header alone may still be fine
+        #[derive(Deserialize)]
+        struct HeaderOnly {
+            header: OaiPmhRecordHeader,
+        }
+        UnparsedRecord {
+            identifier: from_str::<HeaderOnly>(xml).ok().map(|rec| rec.header.identifier),
+            error,
+            xml: String::from(xml),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Identify {
+    #[serde(rename = "repositoryName")]
+    pub repository_name: String,
+    #[serde(rename = "baseURL")]
+    pub base_url: String,
+    #[serde(rename = "protocolVersion")]
+    pub protocol_version: String,
+    #[serde(rename = "adminEmail", default)]
+    pub admin_emails: Vec<String>,
+    #[serde(rename = "earliestDatestamp")]
+    pub earliest_datestamp: String,
+    // no, transient or persistent
+    #[serde(rename = "deletedRecord")]
+    pub deleted_record: String,
+    pub granularity: String,
+}
+
+impl Identify {
+    pub fn granularity(&self) -> Granularity {
+        Granularity::from_oai(&self.granularity)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MetadataFormat {
+    #[serde(rename = "metadataPrefix")]
+    pub metadata_prefix: String,
+    pub schema: String,
+    #[serde(rename = "metadataNamespace")]
+    pub metadata_namespace: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListMetadataFormats {
+    #[serde(rename = "metadataFormat", default)]
+    formats: Vec<MetadataFormat>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OaiSet {
+    #[serde(rename = "setSpec")]
+    pub spec: String,
+    #[serde(rename = "setName")]
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListSets {
+    #[serde(rename = "resumptionToken")]
+    resumption_token: Option<String>,
+    #[serde(rename = "set", default)]
+    sets: Vec<OaiSet>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListIdentifiers {
+    #[serde(rename = "resumptionToken")]
+    resumption_token: Option<String>,
+    #[serde(rename = "header", default)]
+    headers: Vec<OaiPmhRecordHeader>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GetRecord {
+    record: OaiPmhRecord,
 }
 
 #[derive(Debug, Deserialize)]
 struct OaiPmhResponse {
-    #[serde(rename = "responseDate")]
-    response_date: String,
-    request: String,
     error: Option<ResponseError>,
+    #[serde(rename = "Identify")]
+    identify: Option<Identify>,
+    #[serde(rename = "ListMetadataFormats")]
+    list_metadata_formats: Option<ListMetadataFormats>,
+    #[serde(rename = "ListSets")]
+    list_sets: Option<ListSets>,
+    #[serde(rename = "ListIdentifiers")]
+    list_identifiers: Option<ListIdentifiers>,
+    #[serde(rename = "GetRecord")]
+    get_record: Option<GetRecord>,
     #[serde(rename = "ListRecords")]
     list_records: Option<ListRecords>,
 }
 
-fn parse_response (xml: &str) -> OaiPmhResponse {
-    match from_str(xml) {
-        Ok(res) => res,
-        Err(e) => OaiPmhResponse {
-            response_date: String::from("NOW"),
-            request: String::from("Invalid"),
-            error: Some(ResponseError {
-                code: String::from("Invalid XML"),
-                message: e.to_string(),
+impl OaiPmhResponse {
+    // turn an OAI-PMH error into an Err
+    fn checked(self) -> Result<Self, HarvestError> {
+        match self.error {
+            Some(error) => Err(HarvestError::Oai {
+                code: OaiErrorCode::from_code(&error.code),
+                message: error.message,
             }),
-            list_records: None,
-        },
+            None => Ok(self),
+        }
     }
 }
 
-async fn download_url(
-    url: Url
-)-> Result<OaiPmhResponse, Box<dyn std::error::Error>> {
-    println!("Downloading {url}");
-    let res = reqwest::get(url).await?;
-    let status = res.status().as_u16();
-    let content = res.text().await?;
-    if status == 200 {
-        Ok(parse_response(&content))
+// The byte ranges of the <record> elements directly inside <ListRecords>
+fn list_records_spans(xml: &str) -> Result<Vec<Range<usize>>, quick_xml::Error> {
+    let mut reader = Reader::from_str(xml);
+    let mut spans = Vec::new();
+    let mut depth = 0;
+    let mut list_depth = None;
+    let mut record_start = None;
+    loop {
+        let position = reader.buffer_position() as usize;
+        match reader.read_event()? {
+            Event::Start(e) => {
+                depth += 1;
+                match (list_depth, e.local_name().as_ref()) {
+                    (None, b"ListRecords") => list_depth = Some(depth),
+                    (Some(list), b"record") if depth == list + 1 => record_start = Some(position),
+                    _ => (),
+                }
+            },
+            Event::End(_) => {
+                if let (Some(list), Some(start)) = (list_depth, record_start)
+                    && depth == list + 1 {
+                    spans.push(start..reader.buffer_position() as usize);
+                    record_start = None;
+                }
+                if list_depth == Some(depth) {
+                    list_depth = None;
+                }
+                depth -= 1;
+            },
+            Event::Empty(e) => {
+                if let Some(list) = list_depth
+                    && depth == list
+                    && e.local_name().as_ref() == b"record" {
+                    spans.push(position..reader.buffer_position() as usize);
+                }
+            },
+            Event::Eof => return Ok(spans),
+            _ => (),
+        }
+    }
+}
+
+// A ListRecords response is parsed without the records first, then record
+// by record, so a malformed one doesn't take the whole page with it.
+fn parse_response (xml: &str) -> Result<OaiPmhResponse, HarvestError> {
+    let spans = list_records_spans(xml).unwrap_or_default();
+    let mut envelope = String::with_capacity(xml.len());
+    let mut last = 0;
+    for span in &spans {
+        envelope.push_str(&xml[last..span.start]);
+        last = span.end;
+    }
+    envelope.push_str(&xml[last..]);
+    let mut res = from_str::<OaiPmhResponse>(&envelope)?;
+    if let Some(list) = res.list_records.as_mut() {
+        for span in spans {
+            let fragment = &xml[span];
+            match from_str::<OaiPmhRecord>(fragment) {
+                Ok(mut rec) => {
+                    rec.xml = Some(String::from(fragment));
+                    list.records.push(rec);
+                },
+                Err(e) => list.unparsed.push(UnparsedRecord::new(fragment, e.to_string())),
+            }
+        }
+    }
+    Ok(res)
+}
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 5,
+            initial_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(300),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // exponential backoff: initial_delay, then twice as much at each attempt
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.initial_delay.saturating_mul(2u32.saturating_pow(attempt)).min(self.max_delay)
     }
-    else {
-        Err(format!("Status is {status}").into())
+}
+
+// Retry-After can be either a number of seconds or an HTTP date
+fn retry_after(res: &reqwest::Response) -> Option<Duration> {
+    let value = res.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    match value.parse::<u64>() {
+        Ok(secs) => Some(Duration::from_secs(secs)),
+        Err(_) => {
+            let date = DateTime::parse_from_rfc2822(value).ok()?;
+            (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+        }
+    }
+}
+
+async fn download_url(
+    url: Url,
+    retry: &RetryPolicy,
+)-> Result<OaiPmhResponse, HarvestError> {
+    let mut attempt = 0;
+    loop {
+        tracing::debug!(%url, "downloading");
+        let (delay, error) = match reqwest::get(url.clone()).await {
+            Ok(res) => {
+                let status = res.status();
+                if status == StatusCode::OK {
+                    match res.text().await {
+                        // an OAI-PMH error comes with a 200 too
+                        Ok(content) => return parse_response(&content)?.checked(),
+                        Err(e) => (retry.delay(attempt), HarvestError::from(e)),
+                    }
+                }
+                else if status == StatusCode::SERVICE_UNAVAILABLE || status == StatusCode::TOO_MANY_REQUESTS {
+                    let delay = retry_after(&res).map(|d| d.min(retry.max_delay))
+                        .unwrap_or_else(|| retry.delay(attempt));
+                    (delay, HarvestError::Status(status))
+                }
+                else if status.is_server_error() {
+                    (retry.delay(attempt), HarvestError::Status(status))
+                }
+                else {
+                    return Err(HarvestError::Status(status));
+                }
+            },
+            Err(e) => (retry.delay(attempt), HarvestError::from(e)),
+        };
+        if attempt >= retry.max_retries {
+            tracing::warn!(%url, %error, attempt, "giving up");
+            return Err(error);
+        }
+        attempt += 1;
+        tracing::warn!(%url, %error, attempt, max_retries = retry.max_retries, ?delay, "retrying");
+        tokio::time::sleep(delay).await;
     }
 }
 
@@ -747,93 +1724,287 @@ async fn download_url(
 pub struct HarvestParams {
     pub base_url: String,
     pub from: Option<SystemTime>,
-    pub library_id: i32,
     pub site_id: i32,
     pub site_type: SiteType,
+    pub metadata_prefix: String,
+    // empty for the whole repository
+    pub sets: Vec<String>,
+    pub granularity: Granularity,
+    // subtracted from `from` to cover clock skew between us and the repository
+    pub overlap: Duration,
+    pub retry: RetryPolicy,
+    // saved by an interrupted harvest, to pick up where it stopped
+    pub resumption_token: Option<String>,
+    pub resumption_set: Option<String>,
+    // site.field_mapping, shared by the records of the site
+    pub field_mapping: Arc<FieldMapping>,
 }
 
 impl HarvestParams {
-    pub fn harvest_url (&self, token: Option<&str>) -> Url {
+    pub fn harvest_url (&self, set: Option<&str>, token: Option<&str>) -> Url {
         let mut url = Url::parse(&self.base_url).expect("base_url needs to be valid");
-        let metadata_prefix = match self.site_type {
-            // I think this is just a misconfiguration, but that's what we get in our cases
-            SiteType::KohaUnimarc => "marc21",
-            SiteType::KohaMarc21 => "marc21",
-            SiteType::Amusewiki => "marc21",
-        };
-        url.query_pairs_mut().append_pair("verb", "ListRecords")
-            .append_pair("metadataPrefix", metadata_prefix);
+        url.query_pairs_mut().append_pair("verb", "ListRecords");
         match token {
+            // the token is an exclusive argument
             Some(token) => {
                 url.query_pairs_mut().append_pair("resumptionToken", token);
             },
             None => {
-                if let SiteType::Amusewiki = self.site_type {
-                    url.query_pairs_mut().append_pair("set", "web");
+                url.query_pairs_mut().append_pair("metadataPrefix", &self.metadata_prefix);
+                if let Some(set) = set {
+                    url.query_pairs_mut().append_pair("set", set);
                 }
-                if let Some(from_date) = self.from {
-                    let epoch = from_date.duration_since(SystemTime::UNIX_EPOCH).unwrap();
-                    let dt: DateTime<Utc> = DateTime::from_timestamp(epoch.as_secs() as i64,
-                                                                     epoch.subsec_nanos()).unwrap();
-                    let zulu = dt.format("%Y-%m-%dT%H:%M:%SZ").to_string();
-                    println!("Zulu for url {} is {}", &self.base_url, &zulu);
+                if let Some(zulu) = self.harvest_from() {
+                    tracing::debug!(from = %zulu, "incremental harvest");
                     url.query_pairs_mut().append_pair("from", &zulu);
                 }
             }
         };
         url
     }
+    pub fn harvest_from(&self) -> Option<String> {
+        let from_date = self.from?;
+        let from_date = from_date.checked_sub(self.overlap).unwrap_or(SystemTime::UNIX_EPOCH);
+        let epoch = from_date.duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        let dt: DateTime<Utc> = DateTime::from_timestamp(epoch.as_secs() as i64,
+                                                         epoch.subsec_nanos()).unwrap();
+        Some(self.granularity.format(&dt))
+    }
 }
 
-pub async fn harvest(params: &HarvestParams) -> Vec<HarvestedRecord> {
+// site.oai_set holds a list of set specs separated by spaces or commas
+pub fn parse_sets(sets: Option<&str>) -> Vec<String> {
+    match sets {
+        Some(sets) => sets.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|set| !set.is_empty())
+            .map(String::from)
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+#[derive(Debug)]
+pub struct HarvestedPage {
+    pub number: usize,
+    pub set: Option<String>,
+    pub records: Vec<HarvestedRecord>,
+    // malformed records left out, already logged
+    pub unparsed: usize,
+    // the token for the next page, if any
+    pub resumption_token: Option<String>,
+}
+
+// Download the list page by page, set by set, handing each page to the
+// receiving end of `pages` as soon as it's parsed. The channel is bounded,
+// so a slow consumer throttles the downloads. Returns Ok if all the sets
+// were retrieved, or the error that stopped the harvest.
+pub async fn harvest(params: &HarvestParams, pages: Sender<HarvestedPage>) -> Result<(), HarvestError> {
+    let mut sets: Vec<Option<&str>> = params.sets.iter().map(|set| Some(set.as_str())).collect();
+    if sets.is_empty() {
+        sets.push(None);
+    }
+    let mut token = None;
+    if let Some(saved) = &params.resumption_token {
+        // skip the sets the interrupted harvest already went through
+        match sets.iter().position(|set| *set == params.resumption_set.as_deref()) {
+            Some(done) => {
+                sets.drain(..done);
+                token = Some(saved.as_str());
+            },
+            None => tracing::warn!(set = params.resumption_set.as_deref(),
+                                   "saved token belongs to a set no longer configured, ignoring it"),
+        }
+    }
+    for set in sets {
+        harvest_set(params, set, token.take(), &pages).await?;
+    }
+    Ok(())
+}
+
+async fn harvest_set(params: &HarvestParams,
+                     set: Option<&str>,
+                     token: Option<&str>,
+                     pages: &Sender<HarvestedPage>) -> Result<(), HarvestError> {
     let mut interaction = 1;
-    let mut all_records: Vec<HarvestedRecord> = Vec::new();
-    let mut url = params.harvest_url(None);
+    let mut resuming = token.is_some();
+    let mut url = params.harvest_url(set, token);
     loop {
-        match download_url(url.clone()).await {
+        let span = tracing::info_span!("page", number = interaction, set);
+        match download_url(url.clone(), &params.retry).instrument(span).await {
             Ok(res) => {
                 match res.list_records {
                     Some(records) => {
-                        // println!("{} {:?}", url, records);
-                        for rec in records.records {
-                            all_records.push(HarvestedRecord::new(rec, &params));
+                        resuming = false;
+                        records.log_unparsed(&url);
+                        let token = records.resumption_token.filter(|token| token.len() > 1);
+                        let page = HarvestedPage {
+                            number: interaction,
+                            set: set.map(String::from),
+                            records: records.records.into_iter()
+                                .map(|rec| HarvestedRecord::new(rec, params))
+                                .collect(),
+                            unparsed: records.unparsed.len(),
+                            resumption_token: token.clone(),
+                        };
+                        if pages.send(page).await.is_err() {
+                            tracing::warn!(%url, "receiver is gone, stopping");
+                            return Err(HarvestError::Interrupted(String::from("page receiver is gone")));
                         }
-                        if let Some(token) = records.resumption_token {
-                            if token.len() > 1 {
+                        match token {
+                            Some(token) => {
                                 interaction += 1;
-                                println!("{url} download n.{interaction}");
-                                url = params.harvest_url(Some(&token));
+                                tracing::debug!(%url, page = interaction, "next page");
+                                url = params.harvest_url(set, Some(&token));
                                 continue
-                            }
-                        } else {
-                            println!("{url} download completed");
+                            },
+                            None => {
+                                tracing::info!(%url, pages = interaction, "download completed");
+                                return Ok(());
+                            },
                         }
                     },
                     None => {
-                        println!("{url} {res:#?}returned no record");
-                    }
+                        tracing::error!(%url, "no ListRecords element in the response");
+                        return Err(HarvestError::missing("ListRecords"));
+                    },
                 }
             },
-            Err(e) => println!("Error {url}: {e}"),
+            // nothing changed since the last harvest
+            Err(e) if e.is_oai(OaiErrorCode::NoRecordsMatch) => {
+                tracing::info!(%url, "no new records");
+                return Ok(());
+            },
+            // the saved token expired, start over
+            Err(e) if e.is_oai(OaiErrorCode::BadResumptionToken) && resuming => {
+                tracing::warn!(%url, error = %e, "saved token rejected, restarting");
+                resuming = false;
+                url = params.harvest_url(set, None);
+                continue
+            },
+            Err(e) if e.is_oai(OaiErrorCode::CannotDisseminateFormat) => {
+                tracing::error!(%url, error = %e, metadata_prefix = params.metadata_prefix,
+                                "format not provided, check the site configuration");
+                return Err(e);
+            },
+            Err(e) => {
+                tracing::error!(%url, error = %e, "harvest failed");
+                return Err(e);
+            },
         };
-        break
-    };
-    all_records
+    }
+}
+
+fn verb_url(base_url: &str, verb: &str, args: &[(&str, &str)]) -> Url {
+    let mut url = Url::parse(base_url).expect("base_url needs to be valid");
+    url.query_pairs_mut().append_pair("verb", verb).extend_pairs(args);
+    url
+}
+
+pub async fn identify(base_url: &str, retry: &RetryPolicy)
+                      -> Result<Identify, HarvestError> {
+    let res = download_url(verb_url(base_url, "Identify", &[]), retry).await?;
+    res.identify.ok_or_else(|| HarvestError::missing("Identify"))
+}
+
+// the formats available for the whole repository, or for a single item
+pub async fn list_metadata_formats(base_url: &str, identifier: Option<&str>, retry: &RetryPolicy)
+                                   -> Result<Vec<MetadataFormat>, HarvestError> {
+    let args: Vec<(&str, &str)> = identifier.map(|id| ("identifier", id)).into_iter().collect();
+    let res = download_url(verb_url(base_url, "ListMetadataFormats", &args), retry).await?;
+    match res.list_metadata_formats {
+        Some(list) => Ok(list.formats),
+        None => Err(HarvestError::missing("ListMetadataFormats")),
+    }
+}
+
+// repositories without sets answer noSetHierarchy, which is not a failure
+pub async fn list_sets(base_url: &str, retry: &RetryPolicy)
+                       -> Result<Vec<OaiSet>, HarvestError> {
+    let mut all_sets = Vec::new();
+    let mut url = verb_url(base_url, "ListSets", &[]);
+    loop {
+        let res = match download_url(url, retry).await {
+            Err(e) if e.is_oai(OaiErrorCode::NoSetHierarchy) => return Ok(all_sets),
+            res => res?,
+        };
+        let list = res.list_sets.ok_or_else(|| HarvestError::missing("ListSets"))?;
+        all_sets.extend(list.sets);
+        match list.resumption_token.filter(|token| token.len() > 1) {
+            Some(token) => url = verb_url(base_url, "ListSets", &[("resumptionToken", &token)]),
+            None => return Ok(all_sets),
+        }
+    }
+}
+
+// a single ListRecords page, e.g. to sample a repository
+pub async fn list_records(params: &HarvestParams, set: Option<&str>)
+                          -> Result<(Vec<HarvestedRecord>, Vec<UnparsedRecord>), HarvestError> {
+    let url = params.harvest_url(set, None);
+    let res = download_url(url, &params.retry).await?;
+    match res.list_records {
+        Some(list) => Ok((list.records.into_iter().map(|rec| HarvestedRecord::new(rec, params)).collect(),
+                          list.unparsed)),
+        None => Err(HarvestError::missing("ListRecords")),
+    }
+}
+
+// the headers of the records matching the harvest params
+pub async fn list_identifiers(params: &HarvestParams)
+                              -> Result<Vec<OaiPmhRecordHeader>, HarvestError> {
+    let mut all_headers = Vec::new();
+    let mut sets: Vec<Option<&str>> = params.sets.iter().map(|set| Some(set.as_str())).collect();
+    if sets.is_empty() {
+        sets.push(None);
+    }
+    for set in sets {
+        let mut args = vec![("metadataPrefix", params.metadata_prefix.clone())];
+        if let Some(set) = set {
+            args.push(("set", String::from(set)));
+        }
+        if let Some(from) = params.harvest_from() {
+            args.push(("from", from));
+        }
+        loop {
+            let pairs: Vec<(&str, &str)> = args.iter().map(|(k, v)| (*k, v.as_str())).collect();
+            let res = match download_url(verb_url(&params.base_url, "ListIdentifiers", &pairs), &params.retry).await {
+                Err(e) if e.is_oai(OaiErrorCode::NoRecordsMatch) => break,
+                res => res?,
+            };
+            let list = res.list_identifiers.ok_or_else(|| HarvestError::missing("ListIdentifiers"))?;
+            all_headers.extend(list.headers);
+            match list.resumption_token.filter(|token| token.len() > 1) {
+                Some(token) => args = vec![("resumptionToken", token)],
+                None => break,
+            }
+        }
+    }
+    Ok(all_headers)
+}
+
+// fetch a single record, e.g. to refresh it or to debug its mapping
+pub async fn get_record(params: &HarvestParams, identifier: &str)
+                        -> Result<HarvestedRecord, HarvestError> {
+    let url = verb_url(&params.base_url, "GetRecord", &[
+        ("identifier", identifier),
+        ("metadataPrefix", &params.metadata_prefix),
+    ]);
+    let res = download_url(url, &params.retry).await?;
+    match res.get_record {
+        Some(get_record) => Ok(HarvestedRecord::new(get_record.record, params)),
+        None => Err(HarvestError::missing("GetRecord")),
+    }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     #[test]
     fn id1_ok() {
         let rec =  RecordAggregation {
             name: Some(String::from("test")),
             issue: Some(String::from("n.1")),
-            isbn: None,
-            order: None,
             place_date_publisher: None,
             item_identifier: None,
-            linkage: None,
             host: String::from("test-host"),
         };
         assert_eq!(rec.identifier(), "aggregation:test-host:test:n.1");
@@ -845,11 +2016,8 @@ mod tests {
         let rec =  RecordAggregation {
             name: Some(String::from("test")),
             issue: Some(String::from("n.1")),
-            isbn: None,
-            order: None,
             place_date_publisher: (Some(String::from("Some place"))),
             item_identifier: Some(String::from("xxx")),
-            linkage: None,
             host: String::from("test-host"),
         };
         assert_eq!(rec.identifier(), "aggregation:test-host:xxx");
@@ -861,11 +2029,8 @@ mod tests {
         let rec =  RecordAggregation {
             name: Some(String::from("test")),
             issue: None,
-            isbn: None,
-            order: None,
             place_date_publisher: None,
             item_identifier: None,
-            linkage: None,
             host: String::from("test-host"),
         };
         for _ in [1, 2] {
@@ -879,14 +2044,661 @@ mod tests {
         let rec =  RecordAggregation {
             name: None,
             issue: None,
-            isbn: None,
-            order: None,
             place_date_publisher: None,
             item_identifier: None,
-            linkage: None,
             host: String::from("test-host"),
         };
         rec.name();
     }
 
+    pub(crate) fn test_params() -> HarvestParams {
+        HarvestParams {
+            base_url: String::from("https://test-host.org/oai-pmh"),
+            from: None,
+            site_id: 1,
+            site_type: SiteType::KohaMarc21,
+            metadata_prefix: String::from("marc21"),
+            sets: Vec::new(),
+            granularity: Granularity::Second,
+            overlap: Duration::from_secs(0),
+            retry: RetryPolicy::default(),
+            resumption_token: None,
+            resumption_set: None,
+            field_mapping: Arc::new(FieldMapping::default()),
+        }
+    }
+
+    // a GetRecord response, the prefix declared on the root as some
+    // repositories do
+    fn get_record_response(metadata: &str) -> String {
+        format!(r#"<OAI-PMH xmlns="http://www.openarchives.org/OAI/2.0/" xmlns:marc="http://www.loc.gov/MARC21/slim">
+  <responseDate>2025-07-01T10:00:00Z</responseDate>
+  <request verb="GetRecord">https://test-host.org/oai-pmh</request>
+  <GetRecord>
+    <record>
+      <header>
+        <identifier>oai:test-host.org:1</identifier>
+        <datestamp>2025-06-30T11:00:00Z</datestamp>
+      </header>
+      <metadata>{metadata}</metadata>
+    </record>
+  </GetRecord>
+</OAI-PMH>"#)
+    }
+
+    fn marc_metadata(fields: &str) -> String {
+        format!(r#"<record xmlns="http://www.loc.gov/MARC21/slim">{fields}</record>"#)
+    }
+
+    fn marc_record(fields: &str, params: &HarvestParams) -> HarvestedRecord {
+        let xml = get_record_response(&marc_metadata(fields));
+        HarvestedRecord::new(parse_response(&xml).unwrap().get_record.unwrap().record, params)
+    }
+
+    #[test]
+    fn harvest_url_ok() {
+        let mut params = test_params();
+        params.metadata_prefix = String::from("oai_dc");
+        params.sets = parse_sets(Some("web, books  journals"));
+        assert_eq!(params.sets, vec!["web", "books", "journals"]);
+        let url = params.harvest_url(Some("books"), None);
+        assert_eq!(url.as_str(), "https://test-host.org/oai-pmh?verb=ListRecords&metadataPrefix=oai_dc&set=books");
+        let url = params.harvest_url(Some("books"), Some("xxx"));
+        assert_eq!(url.as_str(), "https://test-host.org/oai-pmh?verb=ListRecords&resumptionToken=xxx");
+        assert!(parse_sets(None).is_empty());
+    }
+
+    #[test]
+    fn retry_delay_ok() {
+        let retry = RetryPolicy {
+            max_retries: 5,
+            initial_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(10),
+        };
+        assert_eq!(retry.delay(0), Duration::from_secs(2));
+        assert_eq!(retry.delay(1), Duration::from_secs(4));
+        assert_eq!(retry.delay(2), Duration::from_secs(8));
+        assert_eq!(retry.delay(3), Duration::from_secs(10));
+        assert_eq!(retry.delay(100), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn harvest_from_ok() {
+        let mut params = test_params();
+        assert_eq!(params.harvest_from(), None);
+        params.from = Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1751364000));
+        assert_eq!(params.harvest_from().unwrap(), "2025-07-01T10:00:00Z");
+        params.overlap = Duration::from_secs(3600 * 11);
+        assert_eq!(params.harvest_from().unwrap(), "2025-06-30T23:00:00Z");
+        params.granularity = Granularity::Day;
+        assert_eq!(params.harvest_from().unwrap(), "2025-06-30");
+        let url = params.harvest_url(None, None);
+        assert_eq!(url.query_pairs().find(|(k, _)| k == "from").unwrap().1, "2025-06-30");
+    }
+
+    const DELETED_PAGE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<OAI-PMH xmlns="http://www.openarchives.org/OAI/2.0/">
+  <responseDate>2025-07-01T10:00:00Z</responseDate>
+  <request verb="ListRecords" metadataPrefix="marc21">https://test-host.org/oai-pmh</request>
+  <ListRecords>
+    <record>
+      <header status="deleted">
+        <identifier>oai:test-host.org:1</identifier>
+        <datestamp>2025-06-30T10:00:00Z</datestamp>
+      </header>
+    </record>
+    <record>
+      <header>
+        <identifier>oai:test-host.org:2</identifier>
+        <datestamp>2025-06-30T11:00:00Z</datestamp>
+      </header>
+      <metadata>
+        <record xmlns="http://www.loc.gov/MARC21/slim">
+          <datafield tag="245" ind1="0" ind2="0">
+            <subfield code="a">A title</subfield>
+          </datafield>
+        </record>
+      </metadata>
+    </record>
+    <resumptionToken></resumptionToken>
+  </ListRecords>
+</OAI-PMH>"#;
+
+    #[test]
+    fn deleted_records_ok() {
+        let params = test_params();
+        let res = parse_response(DELETED_PAGE).unwrap();
+        assert!(res.error.is_none());
+        let records: Vec<HarvestedRecord> = res.list_records.unwrap().records.into_iter()
+            .map(|rec| HarvestedRecord::new(rec, &params)).collect();
+        assert_eq!(records.len(), 2);
+        assert!(records[0].is_deleted());
+        assert_eq!(records[0].oai_pmh_identifier(), "oai:test-host.org:1");
+        assert_eq!(records[0].title(), "");
+        assert!(!records[1].is_deleted());
+        assert_eq!(records[1].title(), "A title");
+    }
+
+    #[test]
+    fn raw_xml_ok() {
+        let params = test_params();
+        let res = parse_response(DELETED_PAGE).unwrap();
+        let records: Vec<HarvestedRecord> = res.list_records.unwrap().records.into_iter()
+            .map(|rec| HarvestedRecord::new(rec, &params)).collect();
+        let xml = records[1].raw_xml().unwrap();
+        assert!(xml.starts_with("<record>"));
+        assert!(xml.ends_with("</record>"));
+        let stored = HarvestedRecord::from_xml(xml, &params).unwrap();
+        assert_eq!(stored.oai_pmh_identifier(), "oai:test-host.org:2");
+        assert_eq!(stored.datestamp(), records[1].datestamp());
+        assert_eq!(stored.title(), "A title");
+        assert_eq!(stored.content_checksum(), records[1].content_checksum());
+        assert_eq!(stored.raw_xml(), Some(xml));
+        assert!(HarvestedRecord::from_xml("<record><header>", &params).is_err());
+    }
+
+    #[test]
+    fn identify_ok() {
+        let xml = r#"<OAI-PMH xmlns="http://www.openarchives.org/OAI/2.0/">
+  <responseDate>2025-07-01T10:00:00Z</responseDate>
+  <request verb="Identify">https://test-host.org/oai-pmh</request>
+  <Identify>
+    <repositoryName>Test</repositoryName>
+    <baseURL>https://test-host.org/oai-pmh</baseURL>
+    <protocolVersion>2.0</protocolVersion>
+    <adminEmail>admin@test-host.org</adminEmail>
+    <earliestDatestamp>2010-01-01</earliestDatestamp>
+    <deletedRecord>persistent</deletedRecord>
+    <granularity>YYYY-MM-DD</granularity>
+  </Identify>
+</OAI-PMH>"#;
+        let identify = parse_response(xml).unwrap().checked().unwrap().identify.unwrap();
+        assert_eq!(identify.repository_name, "Test");
+        assert_eq!(identify.admin_emails, vec!["admin@test-host.org"]);
+        assert_eq!(identify.deleted_record, "persistent");
+        assert_eq!(identify.granularity(), Granularity::Day);
+    }
+
+    #[test]
+    fn list_metadata_formats_and_sets_ok() {
+        let xml = r#"<OAI-PMH xmlns="http://www.openarchives.org/OAI/2.0/">
+  <responseDate>2025-07-01T10:00:00Z</responseDate>
+  <request verb="ListMetadataFormats">https://test-host.org/oai-pmh</request>
+  <ListMetadataFormats>
+    <metadataFormat>
+      <metadataPrefix>oai_dc</metadataPrefix>
+      <schema>http://www.openarchives.org/OAI/2.0/oai_dc.xsd</schema>
+      <metadataNamespace>http://www.openarchives.org/OAI/2.0/oai_dc/</metadataNamespace>
+    </metadataFormat>
+    <metadataFormat>
+      <metadataPrefix>marc21</metadataPrefix>
+      <schema>http://www.loc.gov/standards/marcxml/schema/MARC21slim.xsd</schema>
+      <metadataNamespace>http://www.loc.gov/MARC21/slim</metadataNamespace>
+    </metadataFormat>
+  </ListMetadataFormats>
+</OAI-PMH>"#;
+        let formats = parse_response(xml).unwrap().list_metadata_formats.unwrap().formats;
+        assert_eq!(formats.len(), 2);
+        assert_eq!(formats[1].metadata_prefix, "marc21");
+        let xml = r#"<OAI-PMH xmlns="http://www.openarchives.org/OAI/2.0/">
+  <responseDate>2025-07-01T10:00:00Z</responseDate>
+  <request verb="ListSets">https://test-host.org/oai-pmh</request>
+  <ListSets>
+    <set><setSpec>web</setSpec><setName>Web</setName></set>
+    <set><setSpec>books</setSpec><setName>Books</setName></set>
+    <resumptionToken completeListSize="3" cursor="0">sets-2</resumptionToken>
+  </ListSets>
+</OAI-PMH>"#;
+        let list = parse_response(xml).unwrap().list_sets.unwrap();
+        assert_eq!(list.sets.len(), 2);
+        assert_eq!(list.sets[0].spec, "web");
+        assert_eq!(list.resumption_token.unwrap(), "sets-2");
+        let xml = r#"<OAI-PMH xmlns="http://www.openarchives.org/OAI/2.0/">
+  <responseDate>2025-07-01T10:00:00Z</responseDate>
+  <request verb="ListSets">https://test-host.org/oai-pmh</request>
+  <error code="noSetHierarchy">This repository does not support sets</error>
+</OAI-PMH>"#;
+        let res = parse_response(xml).unwrap();
+        assert_eq!(res.error.as_ref().unwrap().code, "noSetHierarchy");
+        let e = res.checked().unwrap_err();
+        assert!(e.is_oai(OaiErrorCode::NoSetHierarchy));
+        assert_eq!(e.to_string(), "noSetHierarchy: This repository does not support sets");
+    }
+
+    #[test]
+    fn list_identifiers_and_get_record_ok() {
+        let xml = r#"<OAI-PMH xmlns="http://www.openarchives.org/OAI/2.0/">
+  <responseDate>2025-07-01T10:00:00Z</responseDate>
+  <request verb="ListIdentifiers">https://test-host.org/oai-pmh</request>
+  <ListIdentifiers>
+    <header>
+      <identifier>oai:test-host.org:1</identifier>
+      <datestamp>2025-06-30T10:00:00Z</datestamp>
+      <setSpec>web</setSpec>
+    </header>
+    <header status="deleted">
+      <identifier>oai:test-host.org:2</identifier>
+      <datestamp>2025-06-30T11:00:00Z</datestamp>
+    </header>
+    <resumptionToken/>
+  </ListIdentifiers>
+</OAI-PMH>"#;
+        let list = parse_response(xml).unwrap().list_identifiers.unwrap();
+        assert_eq!(list.headers.len(), 2);
+        assert_eq!(list.headers[0].identifier(), "oai:test-host.org:1");
+        assert!(!list.headers[0].is_deleted());
+        assert!(list.headers[1].is_deleted());
+        let fields = r#"<datafield tag="245" ind1="0" ind2="0">
+            <subfield code="a">A title</subfield>
+          </datafield>"#;
+        let record = marc_record(fields, &test_params());
+        assert_eq!(record.title(), "A title");
+    }
+
+    #[test]
+    fn dublin_core_ok() {
+        let xml = r#"<OAI-PMH xmlns="http://www.openarchives.org/OAI/2.0/">
+  <responseDate>2025-07-01T10:00:00Z</responseDate>
+  <request verb="ListRecords" metadataPrefix="oai_dc">https://test-host.org/oai-pmh</request>
+  <ListRecords>
+    <record>
+      <header>
+        <identifier>oai:test-host.org:3</identifier>
+        <datestamp>2025-06-30T11:00:00Z</datestamp>
+      </header>
+      <metadata>
+        <oai_dc:dc xmlns:oai_dc="http://www.openarchives.org/OAI/2.0/oai_dc/"
+                   xmlns:dc="http://purl.org/dc/elements/1.1/">
+          <dc:title xml:lang="en">A title</dc:title>
+          <dc:creator>Rossi, Mario</dc:creator>
+          <dc:subject>Anarchism</dc:subject>
+          <dc:creator>Bianchi, Anna</dc:creator>
+          <dc:description>A description</dc:description>
+          <dc:publisher>Some Press</dc:publisher>
+          <dc:date>2019-05-01</dc:date>
+          <dc:identifier>https://elsewhere.org/item/3</dc:identifier>
+          <dc:identifier>https://test-host.org/item/3</dc:identifier>
+          <dc:identifier>urn:isbn:9781234567897</dc:identifier>
+          <dc:language>en-US</dc:language>
+          <dc:format>application/pdf</dc:format>
+        </oai_dc:dc>
+      </metadata>
+    </record>
+  </ListRecords>
+</OAI-PMH>"#;
+        let mut params = test_params();
+        params.site_type = SiteType::Generic;
+        params.metadata_prefix = String::from("oai_dc");
+        let rec = parse_response(xml).unwrap().list_records.unwrap().records.pop().unwrap();
+        let rec = HarvestedRecord::new(rec, &params);
+        assert_eq!(rec.title(), "A title");
+        assert_eq!(rec.authors(), vec!["Rossi, Mario", "Bianchi, Anna"]);
+        assert_eq!(rec.languages(), vec!["en"]);
+        assert_eq!(rec.edition_years(), vec![2019]);
+        assert_eq!(rec.description(), "A description");
+        assert_eq!(rec.publisher(), "Some Press");
+        assert_eq!(rec.isbn(), "9781234567897");
+        assert_eq!(rec.uri().unwrap().uri, "https://test-host.org/item/3");
+        assert_eq!(rec.material_description(), "application/pdf");
+    }
+
+    #[test]
+    fn unimarc_agents_ok() {
+        let fields = r#"<datafield tag="200" ind1="1" ind2=" ">
+            <subfield code="a">Un titolo</subfield>
+            <subfield code="f">di Mario Rossi ; a cura di Anna Bianchi</subfield>
+          </datafield>
+          <datafield tag="700" ind1=" " ind2="1">
+            <subfield code="a">Rossi</subfield>
+            <subfield code="b">Mario</subfield>
+            <subfield code="4">070</subfield>
+          </datafield>
+          <datafield tag="702" ind1=" " ind2="1">
+            <subfield code="a">Bianchi</subfield>
+            <subfield code="b">Anna</subfield>
+            <subfield code="4">340</subfield>
+          </datafield>
+          <datafield tag="712" ind1="0" ind2="2">
+            <subfield code="a">Università di Bologna</subfield>
+            <subfield code="b">Dipartimento di Storia</subfield>
+          </datafield>"#;
+        let mut params = test_params();
+        params.site_type = SiteType::KohaUnimarc;
+        let rec = marc_record(fields, &params);
+        let agents = rec.agents();
+        assert_eq!(agents.len(), 3);
+        assert_eq!(agents[0].name, "Rossi, Mario");
+        assert_eq!(agents[0].kind, AgentKind::Person);
+        assert_eq!(agents[1].name, "Bianchi, Anna");
+        assert_eq!(agents[2].name, "Università di Bologna. Dipartimento di Storia");
+        assert_eq!(agents[2].kind, AgentKind::Corporate);
+        assert_eq!(agents[0].role, AgentRole::Author);
+        assert_eq!(agents[1].role, AgentRole::Editor);
+        assert_eq!(agents[2].role, AgentRole::Contributor);
+        assert_eq!(rec.authors(), vec!["Rossi, Mario"]);
+    }
+
+    #[test]
+    fn marc21_agents_ok() {
+        let fields = r#"<datafield tag="100" ind1="1" ind2=" ">
+            <subfield code="a">Kropotkin, Peter,</subfield>
+            <subfield code="d">1842-1921</subfield>
+          </datafield>
+          <datafield tag="245" ind1="1" ind2="0">
+            <subfield code="a">Mutual aid</subfield>
+          </datafield>
+          <datafield tag="700" ind1="1" ind2=" ">
+            <subfield code="a">Rossi, Mario,</subfield>
+            <subfield code="e">translator.</subfield>
+          </datafield>
+          <datafield tag="700" ind1="1" ind2=" ">
+            <subfield code="a">Bianchi, Anna</subfield>
+            <subfield code="4">edt</subfield>
+          </datafield>
+          <datafield tag="700" ind1="1" ind2=" ">
+            <subfield code="a">Verdi, Luca</subfield>
+          </datafield>
+          <datafield tag="710" ind1="2" ind2=" ">
+            <subfield code="a">Freedom Press.</subfield>
+            <subfield code="b">Editorial board</subfield>
+          </datafield>
+          <datafield tag="711" ind1="2" ind2=" ">
+            <subfield code="a">Anarchist bookfair</subfield>
+            <subfield code="j">author</subfield>
+          </datafield>"#;
+        let rec = marc_record(fields, &test_params());
+        let agents: Vec<(String, &str)> = rec.agents().into_iter()
+            .map(|agent| (agent.name, agent.role.name())).collect();
+        assert_eq!(agents, vec![
+            (String::from("Kropotkin, Peter"), "author"),
+            (String::from("Rossi, Mario"), "translator"),
+            (String::from("Bianchi, Anna"), "editor"),
+            (String::from("Verdi, Luca"), "contributor"),
+            (String::from("Freedom Press. Editorial board"), "contributor"),
+            (String::from("Anarchist bookfair"), "author"),
+        ]);
+        assert_eq!(rec.authors(), vec!["Kropotkin, Peter", "Anarchist bookfair"]);
+        assert_eq!(AgentRole::from_relator("http://id.loc.gov/vocabulary/relators/trl"), Some(AgentRole::Translator));
+        assert_eq!(AgentRole::from_relator("illustrator"), Some(AgentRole::Contributor));
+        assert_eq!(AgentRole::from_relator(" "), None);
+    }
+
+    #[test]
+    fn subjects_ok() {
+        let fields = r#"<datafield tag="245" ind1="1" ind2="0">
+            <subfield code="a">Mutual aid</subfield>
+          </datafield>
+          <datafield tag="650" ind1=" " ind2="0">
+            <subfield code="a">Anarchism</subfield>
+            <subfield code="x">History</subfield>
+            <subfield code="y">19th century.</subfield>
+          </datafield>
+          <datafield tag="651" ind1=" " ind2="0">
+            <subfield code="a">Russia.</subfield>
+          </datafield>
+          <datafield tag="653" ind1=" " ind2=" ">
+            <subfield code="a">anarchism</subfield>
+            <subfield code="a">cooperation</subfield>
+          </datafield>
+          <datafield tag="655" ind1=" " ind2="7">
+            <subfield code="a">Essays</subfield>
+          </datafield>
+          <datafield tag="653" ind1=" " ind2=" ">
+            <subfield code="a">cooperation</subfield>
+          </datafield>"#;
+        let rec = marc_record(fields, &test_params());
+        assert_eq!(rec.subjects(), vec!["Anarchism -- History -- 19th century", "Russia",
+                                        "anarchism", "cooperation", "Essays"]);
+        let mut params = test_params();
+        params.site_type = SiteType::KohaUnimarc;
+        let fields = fields.replace(r#"tag="650""#, r#"tag="606""#)
+            .replace(r#"tag="651""#, r#"tag="607""#)
+            .replace(r#"tag="653""#, r#"tag="610""#);
+        let rec = marc_record(&fields, &params);
+        assert_eq!(rec.subjects(), vec!["Anarchism -- History -- 19th century", "Russia",
+                                        "anarchism", "cooperation"]);
+    }
+
+    #[test]
+    fn content_checksum_ok() {
+        let fields = r#"<datafield tag="100" ind1="1" ind2=" ">
+            <subfield code="a">Kropotkin, Peter</subfield>
+          </datafield>
+          <datafield tag="245" ind1="1" ind2="0">
+            <subfield code="a">Mutual aid</subfield>
+          </datafield>
+          <datafield tag="650" ind1=" " ind2="0">
+            <subfield code="a">Anarchism</subfield>
+          </datafield>"#;
+        let xml = get_record_response(&marc_metadata(fields));
+        let checksums = |xml: &str| {
+            let rec = parse_response(xml).unwrap().get_record.unwrap().record;
+            let rec = HarvestedRecord::new(rec, &test_params());
+            (rec.checksum(), rec.content_checksum())
+        };
+        let (checksum, content_checksum) = checksums(&xml);
+        assert_eq!(checksums(&xml), (checksum.clone(), content_checksum.clone()));
+        // the datestamp is compared on its own
+        let touched = xml.replace("2025-06-30T11:00:00Z", "2025-07-01T09:00:00Z");
+        assert_eq!(checksums(&touched).1, content_checksum);
+        // same entry, different record
+        let changed = xml.replace("Anarchism", "Mutualism");
+        assert_eq!(checksums(&changed).0, checksum);
+        assert_ne!(checksums(&changed).1, content_checksum);
+        let changed = xml.replace(r#"tag="100""#, r#"tag="700""#);
+        assert_ne!(checksums(&changed).1, content_checksum);
+    }
+
+    #[test]
+    fn datestamp_ok() {
+        let fields = r#"<datafield tag="245" ind1="1" ind2="0">
+            <subfield code="a">Mutual aid</subfield>
+          </datafield>"#;
+        let rec = marc_record(fields, &test_params());
+        assert_eq!(rec.parsed_datestamp(), "2025-06-30T11:00:00Z".parse::<DateTime<Utc>>().ok());
+        // day granularity
+        let xml = get_record_response(&marc_metadata(fields)).replace("2025-06-30T11:00:00Z", "2025-06-30");
+        let rec = HarvestedRecord::new(parse_response(&xml).unwrap().get_record.unwrap().record, &test_params());
+        assert_eq!(rec.datestamp(), "2025-06-30");
+        assert_eq!(rec.parsed_datestamp(), "2025-06-30T00:00:00Z".parse::<DateTime<Utc>>().ok());
+        let xml = xml.replace("2025-06-30", "30/06/2025");
+        let rec = HarvestedRecord::new(parse_response(&xml).unwrap().get_record.unwrap().record, &test_params());
+        assert_eq!(rec.parsed_datestamp(), None);
+    }
+
+    #[test]
+    fn field_mapping_ok() {
+        let fields = r#"<datafield tag="100" ind1="1" ind2=" ">
+            <subfield code="a">Kropotkin, Peter</subfield>
+          </datafield>
+          <datafield tag="245" ind1="1" ind2="0">
+            <subfield code="a">Mutual aid</subfield>
+            <subfield code="c">Peter Kropotkin</subfield>
+          </datafield>
+          <datafield tag="650" ind1=" " ind2="0">
+            <subfield code="a">Anarchism</subfield>
+          </datafield>
+          <datafield tag="952" ind1=" " ind2=" ">
+            <subfield code="o">A 123</subfield>
+          </datafield>
+          <datafield tag="995" ind1=" " ind2=" ">
+            <subfield code="k">KRO 1</subfield>
+          </datafield>
+          <datafield tag="991" ind1=" " ind2=" ">
+            <subfield code="a">Fondo Berneri</subfield>
+            <subfield code="b">traduttore</subfield>
+          </datafield>
+          <datafield tag="856" ind1="4" ind2="0">
+            <subfield code="u">https://elsewhere.org/1</subfield>
+          </datafield>
+          <datafield tag="856" ind1="4" ind2="0">
+            <subfield code="u">https://test-host.org/1</subfield>
+            <subfield code="q">text/html</subfield>
+          </datafield>
+          <datafield tag="956" ind1="4" ind2="0">
+            <subfield code="u">https://local.org/1</subfield>
+            <subfield code="z">Local copy</subfield>
+          </datafield>
+          <datafield tag="773" ind1="0" ind2=" ">
+            <subfield code="t">Freedom</subfield>
+            <subfield code="g">n. 1</subfield>
+          </datafield>
+          <datafield tag="461" ind1=" " ind2="1">
+            <subfield code="t">Volontà</subfield>
+            <subfield code="v">2</subfield>
+          </datafield>"#;
+        let default = marc_record(fields, &test_params());
+        assert_eq!(default.title(), "Mutual aid Peter Kropotkin");
+        assert_eq!(default.shelf_location_code(), "A 123");
+        let uri = default.uri().unwrap();
+        assert_eq!((uri.uri.as_str(), uri.content_type.as_str()), ("https://test-host.org/1", "text/html"));
+        let aggregations: Vec<String> = default.aggregations().iter().map(|agg| agg.full_aggregation_name()).collect();
+        assert_eq!(aggregations, vec!["Freedom n. 1"]);
+        let mut params = test_params();
+        params.field_mapping = Arc::new(FieldMapping::from_json(r#"{
+  "title": [{"tag": "245", "subfields": ["a"]}],
+  "shelf_location_code": [{"tag": "995", "subfields": ["k"]}, {"tag": "952", "subfields": ["o"]}],
+  "agents": [{"tag": "991", "subfields": ["a"], "relators": ["b"], "kind": "corporate", "role": "contributor"}],
+  "subjects": [],
+  "uri": [{"tag": "956", "uri": "u", "label": "z"}],
+  "aggregations": [{"tag": "461", "name": "t", "issue": "v"}]
+}"#).unwrap());
+        let rec = default.remap(&params);
+        assert_eq!(rec.title(), "Mutual aid");
+        assert_eq!(rec.shelf_location_code(), "KRO 1 A 123");
+        let agents = rec.agents();
+        assert_eq!(agents.len(), 1);
+        assert_eq!(agents[0].name, "Fondo Berneri");
+        assert_eq!(agents[0].kind, AgentKind::Corporate);
+        assert_eq!(agents[0].role, AgentRole::Translator);
+        assert!(rec.subjects().is_empty());
+        let uri = rec.uri().unwrap();
+        assert_eq!((uri.uri.as_str(), uri.uri_label.as_str()), ("https://local.org/1", "Local copy"));
+        let aggregations: Vec<String> = rec.aggregations().iter().map(|agg| agg.full_aggregation_name()).collect();
+        assert_eq!(aggregations, vec!["Volontà 2"]);
+        assert!(FieldMapping::from_json(r#"{"titel": []}"#).is_err());
+        assert!(FieldMapping::from_json(r#"{"agents": [{"tag": "100", "subfields": ["a"]}]}"#).is_err());
+    }
+
+    #[test]
+    fn leader_and_controlfields_ok() {
+        let fields = r#"<leader>00000nas a2200000 a 4500</leader>
+          <controlfield tag="001">12345</controlfield>
+          <controlfield tag="005">20250630110000.0</controlfield>
+          <controlfield tag="008">850101c19851999it qr p       0   a0ita d</controlfield>
+          <datafield tag="245" ind1="1" ind2="0">
+            <subfield code="a">Umanita Nova</subfield>
+          </datafield>"#;
+        let rec = marc_record(fields, &test_params());
+        assert_eq!(rec.control_field("001"), Some("12345"));
+        assert_eq!(rec.control_field("005"), Some("20250630110000.0"));
+        assert_eq!(rec.identifier(), "12345");
+        assert_eq!(rec.languages(), vec!["it"]);
+        assert_eq!(rec.edition_years(), vec![1985, 1999]);
+        assert_eq!(rec.material_type(), Some(MaterialType::Periodical));
+
+        // explicit fields win over the coded ones
+        let fields = fields.replace("<leader>00000nas", "<leader>00000nam")
+            .replace("c19851999", "s19859999")
+            .replace(r#"<datafield tag="245""#, r#"<datafield tag="041" ind1=" " ind2=" "><subfield code="a">eng</subfield></datafield><datafield tag="245""#);
+        let rec = marc_record(&fields, &test_params());
+        assert_eq!(rec.languages(), vec!["en"]);
+        assert_eq!(rec.edition_years(), vec![1985]);
+        assert_eq!(rec.material_type(), Some(MaterialType::Book));
+
+        // no leader at all, as in the older fixtures
+        let fields = fields.replace("<leader>00000nam a2200000 a 4500</leader>", "");
+        let rec = marc_record(&fields, &test_params());
+        assert_eq!(rec.material_type(), None);
+    }
+
+    #[test]
+    fn free_text_language_ok() {
+        let fields = r#"<datafield tag="245" ind1="1" ind2="0">
+            <subfield code="a">Mutual aid</subfield>
+          </datafield>
+          <datafield tag="546" ind1=" " ind2=" ">
+            <subfield code="a">Text in English</subfield>
+          </datafield>"#;
+        let rec = marc_record(fields, &test_params());
+        assert_eq!(rec.languages(), Vec::<String>::new());
+        // the coded language is better than nothing
+        let fields = fields.replace(r#"<datafield tag="245""#,
+                              r#"<controlfield tag="008">850101s1985    it            000 0 eng d</controlfield><datafield tag="245""#);
+        let rec = marc_record(&fields, &test_params());
+        assert_eq!(rec.languages(), vec!["en"]);
+    }
+
+    #[test]
+    fn marcxml_namespaces_ok() {
+        let fields = r#"<leader>00000nam a2200000 a 4500</leader>
+          <controlfield tag="001">42</controlfield>
+          <datafield tag="100" ind1="1" ind2=" ">
+            <subfield code="a">Malatesta, Errico</subfield>
+          </datafield>
+          <datafield tag="245" ind1="1" ind2="0">
+            <subfield code="a">Anarchy</subfield>
+          </datafield>"#;
+        let prefixed = fields.replace("<", "<marc:").replace("<marc:/", "</marc:");
+        let metadata = [
+            // default namespace on the record
+            marc_metadata(fields),
+            // prefixed
+            format!(r#"<marc:record xmlns:marc="http://www.loc.gov/MARC21/slim">{prefixed}</marc:record>"#),
+            // with a schema location, as Koha does
+            format!(r#"<record xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="http://www.loc.gov/MARC21/slim http://www.loc.gov/standards/marcxml/schema/MARC21slim.xsd" xmlns="http://www.loc.gov/MARC21/slim">{fields}</record>"#),
+            // no namespace on the record, declared on the root
+            format!(r#"<record>{fields}</record>"#),
+            format!(r#"<marc:record>{prefixed}</marc:record>"#),
+            // wrapped in a collection
+            format!(r#"<marc:collection xmlns:marc="http://www.loc.gov/MARC21/slim"><marc:record>{prefixed}</marc:record></marc:collection>"#),
+        ];
+        for md in metadata {
+            let res = parse_response(&get_record_response(&md)).unwrap();
+            assert!(res.error.is_none(), "{md}: {:?}", res.error);
+            let rec = HarvestedRecord::new(res.get_record.unwrap().record, &test_params());
+            assert_eq!(rec.title(), "Anarchy", "{md}");
+            assert_eq!(rec.authors(), vec!["Malatesta, Errico"], "{md}");
+            assert_eq!(rec.control_field("001"), Some("42"), "{md}");
+            assert_eq!(rec.material_type(), Some(MaterialType::Book), "{md}");
+        }
+    }
+
+    #[test]
+    fn malformed_record_ok() {
+        let xml = DELETED_PAGE
+            .replace("<resumptionToken></resumptionToken>", r#"<record>
+      <header>
+        <identifier>oai:test-host.org:3</identifier>
+        <datestamp>2025-06-30T12:00:00Z</datestamp>
+      </header>
+      <metadata>
+        <record xmlns="http://www.loc.gov/MARC21/slim">
+          <datafield tag="245" ind1="0" ind2="0">
+            <subfield>No code</subfield>
+          </datafield>
+        </record>
+      </metadata>
+    </record>
+    <record><header><datestamp>2025-06-30T12:00:00Z</datestamp></header></record>
+    <record/>
+    <resumptionToken cursor="0">next-page</resumptionToken>"#);
+        let res = parse_response(&xml).unwrap();
+        assert!(res.error.is_none(), "{:?}", res.error);
+        let list = res.list_records.unwrap();
+        assert_eq!(list.resumption_token.unwrap(), "next-page");
+        assert_eq!(list.records.len(), 2);
+        assert_eq!(list.records[1].header.identifier(), "oai:test-host.org:2");
+        assert_eq!(list.unparsed.len(), 3);
+        assert_eq!(list.unparsed[0].identifier.as_deref(), Some("oai:test-host.org:3"));
+        assert!(list.unparsed[0].xml.starts_with("<record>"));
+        assert!(list.unparsed[0].xml.contains("No code"));
+        assert!(list.unparsed[0].xml.ends_with("</record>"));
+        assert_eq!(list.unparsed[1].identifier, None);
+        assert_eq!(list.unparsed[2].xml, "<record/>");
+
+        // not even XML
+        let res = parse_response("<OAI-PMH><ListRecords><record></ListRecords>");
+        assert!(matches!(res, Err(HarvestError::Xml(_))));
+    }
 }
@@ -0,0 +1,292 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+use crate::oai::pmh::{self, FieldMapping, Granularity, HarvestParams, HarvestedRecord, RetryPolicy, SiteType, UnparsedRecord};
+
+// metadata prefixes we know how to map, in order of preference
+const KNOWN_PREFIXES: [&str; 3] = ["marc21", "marcxml", "oai_dc"];
+
+#[derive(Debug, Default)]
+pub struct ProbeReport {
+    pub base_url: String,
+    pub identify: Vec<String>,
+    pub metadata_formats: Vec<String>,
+    pub sets: Vec<String>,
+    pub site_type: Option<SiteType>,
+    pub metadata_prefix: Option<String>,
+    pub granularity: Option<String>,
+    pub records: usize,
+    pub deleted: usize,
+    pub with_title: usize,
+    pub with_authors: usize,
+    pub with_languages: usize,
+    pub with_uri: usize,
+    pub errors: Vec<String>,
+}
+
+impl fmt::Display for ProbeReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Probe of {}", self.base_url)?;
+        for line in &self.identify {
+            writeln!(f, "  {line}")?;
+        }
+        writeln!(f, "Metadata formats:")?;
+        for format in &self.metadata_formats {
+            writeln!(f, "  {format}")?;
+        }
+        writeln!(f, "Sets:")?;
+        for set in &self.sets {
+            writeln!(f, "  {set}")?;
+        }
+        writeln!(f, "Detected site type: {}", self.site_type.as_ref().map_or("unknown", |t| t.name()))?;
+        writeln!(f, "Metadata format: {}", self.metadata_prefix.as_deref().unwrap_or("none usable"))?;
+        writeln!(f, "Granularity: {}", self.granularity.as_deref().unwrap_or("unknown"))?;
+        let live = self.records - self.deleted;
+        writeln!(f, "Sample: {} records, {} deleted", self.records, self.deleted)?;
+        writeln!(f, "  with title: {}/{live}", self.with_title)?;
+        writeln!(f, "  with authors: {}/{live}", self.with_authors)?;
+        writeln!(f, "  with languages: {}/{live}", self.with_languages)?;
+        writeln!(f, "  with uri: {}/{live}", self.with_uri)?;
+        writeln!(f, "Errors: {}", self.errors.len())?;
+        for error in &self.errors {
+            writeln!(f, "  {error}")?;
+        }
+        Ok(())
+    }
+}
+
+// Guess the site type from the URL and the sets. Koha flavour is decided
+// later, looking at the sample records.
+fn guess_site_type(base_url: &str, sets: &[String]) -> SiteType {
+    let path = Url::parse(base_url).map(|u| u.path().to_string()).unwrap_or_default();
+    if path.contains("koha") {
+        SiteType::KohaMarc21
+    }
+    else if path.ends_with("/oai-pmh") && sets.iter().any(|set| set == "web") {
+        SiteType::Amusewiki
+    }
+    else {
+        SiteType::Generic
+    }
+}
+
+// Check a new OAI-PMH endpoint before it goes into the site table
+pub async fn probe(base_url: &str) -> ProbeReport {
+    let retry = RetryPolicy {
+        max_retries: 1,
+        ..RetryPolicy::default()
+    };
+    let mut report = ProbeReport {
+        base_url: String::from(base_url),
+        ..ProbeReport::default()
+    };
+    if let Err(e) = Url::parse(base_url) {
+        report.errors.push(format!("Invalid URL: {e}"));
+        return report;
+    }
+    match pmh::identify(base_url, &retry).await {
+        Ok(identify) => {
+            report.identify = vec![
+                format!("Repository name: {}", identify.repository_name),
+                format!("Base URL: {}", identify.base_url),
+                format!("Protocol version: {}", identify.protocol_version),
+                format!("Admin emails: {}", identify.admin_emails.join(", ")),
+                format!("Earliest datestamp: {}", identify.earliest_datestamp),
+                format!("Deleted records: {}", identify.deleted_record),
+            ];
+            report.granularity = Some(identify.granularity.clone());
+        },
+        Err(e) => report.errors.push(format!("Identify: {e}")),
+    }
+    let mut prefixes = Vec::new();
+    match pmh::list_metadata_formats(base_url, None, &retry).await {
+        Ok(formats) => {
+            for format in formats {
+                report.metadata_formats.push(format!("{} ({}, {})", format.metadata_prefix,
+                                                     format.metadata_namespace, format.schema));
+                prefixes.push(format.metadata_prefix);
+            }
+        },
+        Err(e) => report.errors.push(format!("ListMetadataFormats: {e}")),
+    }
+    let mut set_specs = Vec::new();
+    match pmh::list_sets(base_url, &retry).await {
+        Ok(sets) => {
+            for set in sets {
+                report.sets.push(format!("{} ({})", set.spec, set.name));
+                set_specs.push(set.spec);
+            }
+        },
+        Err(e) => report.errors.push(format!("ListSets: {e}")),
+    }
+    report.metadata_prefix = KNOWN_PREFIXES.iter()
+        .find(|known| prefixes.iter().any(|p| p == *known))
+        .map(|known| String::from(*known));
+    let Some(metadata_prefix) = report.metadata_prefix.clone() else {
+        report.errors.push(String::from("No supported metadata format"));
+        return report;
+    };
+    report.site_type = Some(guess_site_type(base_url, &set_specs));
+    let mut params = HarvestParams {
+        base_url: String::from(base_url),
+        from: None,
+        site_id: 0,
+        site_type: report.site_type.clone().unwrap_or(SiteType::Generic),
+        metadata_prefix,
+        sets: Vec::new(),
+        granularity: report.granularity.as_deref().map_or(Granularity::Second, Granularity::from_oai),
+        overlap: Duration::from_secs(0),
+        retry,
+        resumption_token: None,
+        resumption_set: None,
+        field_mapping: Arc::new(FieldMapping::default()),
+    };
+    let set = match report.site_type {
+        Some(SiteType::Amusewiki) => Some("web"),
+        _ => None,
+    };
+    let (mut records, unparsed) = match pmh::list_records(&params, set).await {
+        Ok(listed) => listed,
+        Err(e) => {
+            report.errors.push(format!("ListRecords: {e}"));
+            return report;
+        }
+    };
+    if let Some(SiteType::KohaMarc21) = report.site_type
+        && is_unimarc(&records) {
+        report.site_type = Some(SiteType::KohaUnimarc);
+        params.site_type = SiteType::KohaUnimarc;
+        records = records.into_iter().map(|rec| rec.remap(&params)).collect();
+    }
+    check_records(&mut report, &records, &unparsed);
+    report
+}
+
+// UNIMARC has the title in 200, MARC21 in 245
+fn is_unimarc(records: &[HarvestedRecord]) -> bool {
+    let unimarc = records.iter().filter(|rec| rec.has_field("200")).count();
+    let marc21 = records.iter().filter(|rec| rec.has_field("245")).count();
+    unimarc > marc21
+}
+
+fn check_records(report: &mut ProbeReport, records: &[HarvestedRecord], unparsed: &[UnparsedRecord]) {
+    for rec in unparsed {
+        report.records += 1;
+        report.errors.push(format!("{}: {}", rec.identifier.as_deref().unwrap_or("record without identifier"), rec.error));
+    }
+    for rec in records {
+        report.records += 1;
+        if rec.is_deleted() {
+            report.deleted += 1;
+            continue;
+        }
+        if !rec.title().trim().is_empty() {
+            report.with_title += 1;
+        }
+        else {
+            report.errors.push(format!("{}: no title", rec.oai_pmh_identifier()));
+        }
+        if !rec.authors().is_empty() {
+            report.with_authors += 1;
+        }
+        if !rec.languages().is_empty() {
+            report.with_languages += 1;
+        }
+        if rec.uri().is_some() {
+            report.with_uri += 1;
+        }
+        // day granularity datestamps are fine too
+        if rec.parsed_datestamp().is_none() {
+            report.errors.push(format!("{}: bad datestamp {}", rec.oai_pmh_identifier(), rec.datestamp()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oai::pmh::tests::test_params;
+
+    fn record(identifier: &str, datestamp: &str, fields: &str, params: &HarvestParams) -> HarvestedRecord {
+        let xml = format!(r#"<record><header><identifier>{identifier}</identifier><datestamp>{datestamp}</datestamp></header>
+<metadata><record xmlns="http://www.loc.gov/MARC21/slim">{fields}</record></metadata></record>"#);
+        HarvestedRecord::from_xml(&xml, params).unwrap()
+    }
+
+    #[test]
+    fn guess_site_type_ok() {
+        let web = vec![String::from("web")];
+        assert_eq!(guess_site_type("https://library.org/cgi-bin/koha/oai.pl", &[]).name(), SiteType::KohaMarc21.name());
+        assert_eq!(guess_site_type("https://texts.org/oai-pmh", &web).name(), SiteType::Amusewiki.name());
+        assert_eq!(guess_site_type("https://texts.org/oai-pmh", &[]).name(), SiteType::Generic.name());
+        assert_eq!(guess_site_type("https://repository.org/oai/request", &web).name(), SiteType::Generic.name());
+        assert_eq!(guess_site_type("not a url", &web).name(), SiteType::Generic.name());
+    }
+
+    #[test]
+    fn unimarc_detection_ok() {
+        let mut params = test_params();
+        let marc21 = r#"<datafield tag="100" ind1="1" ind2=" "><subfield code="a">Rossi, Mario</subfield></datafield>
+<datafield tag="245" ind1="1" ind2="0"><subfield code="a">Un titolo</subfield></datafield>"#;
+        let unimarc = r#"<datafield tag="200" ind1="1" ind2=" "><subfield code="a">Un titolo</subfield></datafield>
+<datafield tag="700" ind1=" " ind2="1"><subfield code="a">Rossi</subfield><subfield code="b">Mario</subfield></datafield>"#;
+        let records = vec![
+            record("oai:test-host.org:1", "2025-06-30T11:00:00Z", marc21, &params),
+            record("oai:test-host.org:2", "2025-06-30T11:00:00Z", unimarc, &params),
+        ];
+        assert!(!is_unimarc(&records));
+        let records = vec![
+            record("oai:test-host.org:2", "2025-06-30T11:00:00Z", unimarc, &params),
+            record("oai:test-host.org:3", "2025-06-30T11:00:00Z", unimarc, &params),
+        ];
+        assert!(is_unimarc(&records));
+        // read as MARC21 the UNIMARC records have no title
+        assert_eq!(records[0].title(), "");
+        params.site_type = SiteType::KohaUnimarc;
+        let remapped = records.into_iter().next().unwrap().remap(&params);
+        assert_eq!(remapped.title(), "Un titolo");
+        assert_eq!(remapped.authors(), vec!["Rossi, Mario"]);
+    }
+
+    #[test]
+    fn check_records_ok() {
+        let params = test_params();
+        let fields = r#"<datafield tag="100" ind1="1" ind2=" "><subfield code="a">Rossi, Mario</subfield></datafield>
+<datafield tag="245" ind1="1" ind2="0"><subfield code="a">Un titolo</subfield></datafield>
+<datafield tag="856" ind1="4" ind2="0"><subfield code="u">https://test-host.org/1</subfield></datafield>"#;
+        let records = vec![
+            record("oai:test-host.org:1", "2025-06-30T11:00:00Z", fields, &params),
+            record("oai:test-host.org:2", "2025-06-30", fields, &params),
+            record("oai:test-host.org:3", "30/06/2025", "", &params),
+            HarvestedRecord::from_xml(r#"<record><header status="deleted"><identifier>oai:test-host.org:4</identifier><datestamp>2025-06-30T11:00:00Z</datestamp></header></record>"#, &params).unwrap(),
+        ];
+        let unparsed = vec![
+            UnparsedRecord {
+                identifier: Some(String::from("oai:test-host.org:5")),
+                error: String::from("missing field `@code`"),
+                xml: String::new(),
+            },
+            UnparsedRecord {
+                identifier: None,
+                error: String::from("missing field `header`"),
+                xml: String::new(),
+            },
+        ];
+        let mut report = ProbeReport::default();
+        check_records(&mut report, &records, &unparsed);
+        assert_eq!(report.records, 6);
+        assert_eq!(report.deleted, 1);
+        assert_eq!(report.with_title, 2);
+        assert_eq!(report.with_authors, 2);
+        assert_eq!(report.with_languages, 0);
+        assert_eq!(report.with_uri, 2);
+        assert_eq!(report.errors, vec![
+            "oai:test-host.org:5: missing field `@code`",
+            "record without identifier: missing field `header`",
+            "oai:test-host.org:3: no title",
+            "oai:test-host.org:3: bad datestamp 30/06/2025",
+        ]);
+        assert!(report.to_string().contains("  with title: 2/5\n"));
+    }
+}
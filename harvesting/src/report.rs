@@ -0,0 +1,180 @@
+use std::fmt;
+use serde::Serialize;
+use crate::oai::pmh::HarvestedPage;
+
+// What happened to a site during a harvest
+#[derive(Debug, Default, Serialize)]
+pub struct SiteReport {
+    pub site_id: i32,
+    pub base_url: String,
+    // all the sets were downloaded and stored
+    pub completed: bool,
+    pub pages: usize,
+    pub records: usize,
+    pub inserted: usize,
+    pub updated: usize,
+    // same datestamp and content as stored, not written
+    pub unchanged: usize,
+    pub deleted: usize,
+    // malformed, or impossible to map
+    pub skipped: usize,
+    pub failed: usize,
+    pub full_texts: usize,
+    pub elapsed_secs: f64,
+    // what stopped the harvest
+    pub error: Option<String>,
+    pub last_resumption_token: Option<String>,
+}
+
+impl SiteReport {
+    // as stored in harvest_run.status
+    pub fn status(&self) -> &'static str {
+        if self.error.is_some() {
+            "failed"
+        }
+        else if !self.completed || self.failed > 0 {
+            "incomplete"
+        }
+        else {
+            "completed"
+        }
+    }
+    // what a dry run can tell without the database: the live records
+    // end up in neither inserted nor updated
+    pub fn count_page(&mut self, page: &HarvestedPage) {
+        self.pages += 1;
+        self.records += page.records.len() + page.unparsed;
+        self.skipped += page.unparsed;
+        for res in &page.records {
+            if res.is_deleted() {
+                self.deleted += 1;
+            }
+            else if !res.has_metadata() {
+                self.skipped += 1;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunReport {
+    pub started: String,
+    pub dry_run: bool,
+    pub elapsed_secs: f64,
+    pub sites: Vec<SiteReport>,
+}
+
+impl RunReport {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("the report is always serializable")
+    }
+}
+
+impl fmt::Display for RunReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = if self.dry_run { "Dry run" } else { "Harvest" };
+        writeln!(f, "{kind} started {}, {:.1}s", self.started, self.elapsed_secs)?;
+        for site in &self.sites {
+            writeln!(f, "{} ({})", site.base_url, site.status())?;
+            writeln!(f, "  pages: {}, records: {}, inserted: {}, updated: {}, unchanged: {}, deleted: {}",
+                     site.pages, site.records, site.inserted, site.updated, site.unchanged, site.deleted)?;
+            writeln!(f, "  skipped: {}, failed: {}, full texts: {}, elapsed: {:.1}s",
+                     site.skipped, site.failed, site.full_texts, site.elapsed_secs)?;
+            if let Some(error) = &site.error {
+                writeln!(f, "  error: {error}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oai::pmh::HarvestedRecord;
+    use crate::oai::pmh::tests::test_params;
+
+    fn record(header: &str, metadata: &str) -> HarvestedRecord {
+        let xml = format!("<record>{header}{metadata}</record>");
+        HarvestedRecord::from_xml(&xml, &test_params()).unwrap()
+    }
+
+    #[test]
+    fn status_ok() {
+        let mut site = SiteReport::default();
+        assert_eq!(site.status(), "incomplete");
+        site.completed = true;
+        assert_eq!(site.status(), "completed");
+        site.failed = 1;
+        assert_eq!(site.status(), "incomplete");
+        site.error = Some(String::from("Interrupted"));
+        assert_eq!(site.status(), "failed");
+        site.completed = false;
+        site.failed = 0;
+        assert_eq!(site.status(), "failed");
+    }
+
+    #[test]
+    fn count_page_ok() {
+        let metadata = r#"<metadata><record xmlns="http://www.loc.gov/MARC21/slim">
+  <datafield tag="245" ind1="0" ind2="0"><subfield code="a">A title</subfield></datafield>
+</record></metadata>"#;
+        let page = HarvestedPage {
+            number: 1,
+            set: None,
+            records: vec![
+                record(r#"<header status="deleted"><identifier>oai:test-host.org:1</identifier><datestamp>2025-06-30T10:00:00Z</datestamp></header>"#, ""),
+                record("<header><identifier>oai:test-host.org:2</identifier><datestamp>2025-06-30T11:00:00Z</datestamp></header>", metadata),
+                // neither deleted nor with metadata
+                record("<header><identifier>oai:test-host.org:3</identifier><datestamp>2025-06-30T11:00:00Z</datestamp></header>", ""),
+            ],
+            unparsed: 2,
+            resumption_token: None,
+        };
+        let mut site = SiteReport::default();
+        site.count_page(&page);
+        site.count_page(&page);
+        assert_eq!(site.pages, 2);
+        assert_eq!(site.records, 10);
+        assert_eq!(site.deleted, 2);
+        assert_eq!(site.skipped, 6);
+        assert_eq!(site.inserted + site.updated, 0);
+    }
+
+    #[test]
+    fn run_report_ok() {
+        let report = RunReport {
+            started: String::from("2025-07-01 10:00:00"),
+            dry_run: true,
+            elapsed_secs: 2.04,
+            sites: vec![
+                SiteReport {
+                    site_id: 1,
+                    base_url: String::from("https://test-host.org/oai-pmh"),
+                    completed: true,
+                    records: 3,
+                    ..SiteReport::default()
+                },
+                SiteReport {
+                    site_id: 2,
+                    base_url: String::from("https://other-host.org/oai-pmh"),
+                    error: Some(String::from("HTTP status 503")),
+                    ..SiteReport::default()
+                },
+            ],
+        };
+        let text = report.to_string();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "Dry run started 2025-07-01 10:00:00, 2.0s");
+        assert_eq!(lines[1], "https://test-host.org/oai-pmh (completed)");
+        assert_eq!(lines[2], "  pages: 0, records: 3, inserted: 0, updated: 0, unchanged: 0, deleted: 0");
+        assert_eq!(lines[4], "https://other-host.org/oai-pmh (failed)");
+        assert_eq!(lines[7], "  error: HTTP status 503");
+        assert_eq!(lines.len(), 8);
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(json["dry_run"], true);
+        assert_eq!(json["sites"][0]["records"], 3);
+        assert_eq!(json["sites"][1]["error"], "HTTP status 503");
+        assert_eq!(json["sites"][0]["error"], serde_json::Value::Null);
+    }
+}
@@ -0,0 +1,3 @@
+-- book, article, periodical, audio or video, from the MARC leader
+ALTER TABLE datasource ADD COLUMN material_type VARCHAR(32);
+CREATE INDEX datasource_material_type_idx ON datasource (material_type);
@@ -0,0 +1,20 @@
+-- one row per site per harvester run, written as it goes
+CREATE TABLE harvest_run (
+    harvest_run_id SERIAL PRIMARY KEY,
+    site_id INTEGER NOT NULL REFERENCES site(site_id) ON UPDATE CASCADE ON DELETE CASCADE,
+    started TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    finished TIMESTAMP WITH TIME ZONE,
+    -- running, completed, incomplete or failed
+    status VARCHAR(32) NOT NULL DEFAULT 'running',
+    pages INTEGER NOT NULL DEFAULT 0,
+    records INTEGER NOT NULL DEFAULT 0,
+    inserted INTEGER NOT NULL DEFAULT 0,
+    updated INTEGER NOT NULL DEFAULT 0,
+    deleted INTEGER NOT NULL DEFAULT 0,
+    skipped INTEGER NOT NULL DEFAULT 0,
+    failed INTEGER NOT NULL DEFAULT 0,
+    full_texts INTEGER NOT NULL DEFAULT 0,
+    error_message TEXT,
+    last_resumption_token TEXT
+);
+CREATE INDEX harvest_run_site_id_started_idx ON harvest_run (site_id, started DESC);
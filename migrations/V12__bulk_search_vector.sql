@@ -0,0 +1,38 @@
+-- the bulk loader sets collector.skip_search_vector for its transaction
+-- and recomputes the vectors once per entry at the end
+CREATE OR REPLACE FUNCTION update_search_vector() RETURNS TRIGGER AS $$
+DECLARE target_entry_id INTEGER;
+DECLARE title_text TEXT;
+DECLARE agent_names TEXT;
+DECLARE full_text TEXT;
+BEGIN
+    IF current_setting('collector.skip_search_vector', true) = 'on' THEN
+        RETURN NULL;
+    END IF;
+
+    IF TG_OP = 'DELETE' THEN
+        target_entry_id := OLD.entry_id;
+    ELSE
+        target_entry_id := NEW.entry_id;
+    END IF;
+
+    SELECT string_agg(e.search_text, ' ') INTO title_text
+    FROM entry e WHERE e.entry_id = target_entry_id;
+
+    SELECT string_agg(a.search_text, ' ') INTO agent_names
+    FROM agent a
+    INNER JOIN entry_agent ea ON a.agent_id = ea.agent_id
+    WHERE ea.entry_id = target_entry_id;
+
+    SELECT string_agg(ds.search_text, ' ') INTO full_text
+    FROM datasource ds
+    WHERE ds.entry_id = target_entry_id;
+
+    UPDATE entry SET search_vector =
+          setweight(to_tsvector(COALESCE(title_text, '')), 'A') ||
+          setweight(to_tsvector(COALESCE(agent_names, '')), 'B') ||
+          setweight(to_tsvector(COALESCE(full_text, '')), 'C')
+    WHERE entry_id = target_entry_id;
+    RETURN NULL;
+END
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,4 @@
+-- what the datasource was written from, to skip the unchanged records
+ALTER TABLE datasource ADD COLUMN content_checksum VARCHAR(64);
+
+ALTER TABLE harvest_run ADD COLUMN unchanged INTEGER NOT NULL DEFAULT 0;
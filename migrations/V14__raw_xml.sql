@@ -0,0 +1,2 @@
+-- the <record> element as harvested, to map it again without the network
+ALTER TABLE datasource ADD COLUMN raw_xml TEXT;
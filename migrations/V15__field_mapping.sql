@@ -0,0 +1,5 @@
+-- per-site overrides of the MARC fields feeding title, shelf location and
+-- so on, like {"shelf_location_code": [{"tag": "995", "subfields": ["k"]}]}
+-- or {"uri": [{"tag": "952", "uri": "u"}]}. Every accessor can be mapped.
+-- NULL keeps the defaults. Run a reindex of the site after changing it.
+ALTER TABLE site ADD COLUMN field_mapping JSONB;
@@ -0,0 +1,9 @@
+-- to find the entries left without a datasource
+CREATE INDEX IF NOT EXISTS datasource_entry_id_idx ON datasource (entry_id);
+
+-- The agent, UNIMARC and language mapping changes give most MARC records
+-- a new checksum, hence a new entry: after upgrading, harvest every site
+-- with --full, so the records move to their new entries. A reindex is not
+-- enough, the records harvested before V14 have no raw XML to map again.
+-- The entries left behind are dropped by that harvest, nothing is orphaned
+-- before it.
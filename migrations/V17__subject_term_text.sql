@@ -0,0 +1,2 @@
+-- headings with their subdivisions easily go over 255 characters
+ALTER TABLE subject ALTER COLUMN term TYPE TEXT;
@@ -0,0 +1,2 @@
+-- datestamp granularity advertised by the repository (Identify)
+ALTER TABLE site ADD COLUMN oai_granularity VARCHAR(32) NOT NULL DEFAULT 'YYYY-MM-DDThh:mm:ssZ';
@@ -0,0 +1,3 @@
+-- state of an ongoing harvest, so an interrupted one can be resumed
+ALTER TABLE site ADD COLUMN harvest_resumption_token TEXT;
+ALTER TABLE site ADD COLUMN harvest_started TIMESTAMP WITH TIME ZONE;
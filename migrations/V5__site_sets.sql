@@ -0,0 +1,9 @@
+-- oai_set can hold several set specs, separated by spaces or commas
+ALTER TABLE site ALTER COLUMN oai_set TYPE VARCHAR(1024);
+
+-- the set the saved resumption token belongs to
+ALTER TABLE site ADD COLUMN harvest_set VARCHAR(255);
+
+-- these used to be hardcoded in the harvester
+UPDATE site SET oai_set = 'web' WHERE site_type = 'amusewiki' AND oai_set IS NULL;
+UPDATE site SET oai_metadata_format = 'marc21' WHERE oai_metadata_format IS NULL;
@@ -0,0 +1,2 @@
+-- deletedRecord policy advertised by the repository (Identify)
+ALTER TABLE site ADD COLUMN oai_deleted_record VARCHAR(32);
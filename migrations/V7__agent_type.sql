@@ -0,0 +1,2 @@
+-- person or corporate
+ALTER TABLE agent ADD COLUMN agent_type VARCHAR(16) NOT NULL DEFAULT 'person';
@@ -0,0 +1,6 @@
+-- author, translator, editor or contributor. The same agent can appear
+-- with different roles on the same entry.
+ALTER TABLE entry_agent ADD COLUMN agent_role VARCHAR(16) NOT NULL DEFAULT 'author';
+ALTER TABLE entry_agent DROP CONSTRAINT entry_agent_pkey;
+ALTER TABLE entry_agent ADD PRIMARY KEY (entry_id, agent_id, agent_role);
+CREATE INDEX entry_agent_agent_role_idx ON entry_agent (agent_id, agent_role);
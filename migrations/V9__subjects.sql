@@ -0,0 +1,18 @@
+-- subject headings, as found in the records
+CREATE TABLE subject (
+    subject_id SERIAL PRIMARY KEY,
+    term VARCHAR(255) NOT NULL,
+    search_text TEXT,
+    created TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_modified TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE(term)
+);
+
+CREATE TABLE entry_subject (
+    entry_id INTEGER NOT NULL REFERENCES entry(entry_id) ON UPDATE CASCADE ON DELETE CASCADE,
+    subject_id INTEGER NOT NULL REFERENCES subject(subject_id) ON UPDATE CASCADE ON DELETE CASCADE,
+    created TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_modified TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (entry_id, subject_id)
+);
+CREATE INDEX entry_subject_subject_id_idx ON entry_subject (subject_id);
@@ -3,7 +3,7 @@ use std::collections::HashMap;
 use axum::{
     extract::{State, Query},
     routing::get,
-    http::StatusCode,
+    http::{HeaderMap, StatusCode},
     Router,
     Json,
 };
@@ -11,7 +11,7 @@ use tokio_postgres::NoTls;
 use bb8::{Pool};
 use bb8_postgres::PostgresConnectionManager;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 
 type ConnectionPool = Pool<PostgresConnectionManager<NoTls>>;
 
@@ -20,6 +20,13 @@ struct Entry {
     entry_id: i32,
     rank: f32,
     title: String,
+    contributors: Vec<ContributorGroup>,
+}
+
+#[derive(Serialize, Debug)]
+struct ContributorGroup {
+    role: String,
+    names: Vec<String>,
 }
 
 #[derive(Serialize, Debug)]
@@ -27,8 +34,31 @@ struct Facet {
     count: i64,
     term: String,
     id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<String>,
 }
 
+// listing order of the contributor groups
+const AGENT_ROLES: [&str; 4] = ["author", "translator", "editor", "contributor"];
+
+// Restrict the entries to the ones with the creator ($2, an agent_id) in
+// the given role ($3). Either can be NULL.
+const AGENT_FILTER: &str = r#"
+(($2::INTEGER IS NULL AND $3::TEXT IS NULL)
+ OR EXISTS (SELECT 1 FROM entry_agent fa
+            WHERE fa.entry_id = e.entry_id
+            AND ($2::INTEGER IS NULL OR fa.agent_id = $2)
+            AND ($3::TEXT IS NULL OR fa.agent_role = $3)))
+"#;
+
+// Restrict the entries to the ones with the subject $4 (a subject_id),
+// unless NULL.
+const SUBJECT_FILTER: &str = r#"
+($4::INTEGER IS NULL
+ OR EXISTS (SELECT 1 FROM entry_subject fs
+            WHERE fs.entry_id = e.entry_id AND fs.subject_id = $4))
+"#;
+
 #[derive(Serialize, Debug)]
 struct FacetList {
     name: String,
@@ -40,6 +70,8 @@ struct FacetBlock {
     library: FacetList,
     creator: FacetList,
     language: FacetList,
+    subject: FacetList,
+    material_type: FacetList,
 }
 
 #[derive(Serialize, Debug)]
@@ -53,27 +85,59 @@ async fn search(
     Query(params): Query<HashMap<String, String>>,
 ) -> (StatusCode, Json<SearchResult>) {
     let conn = pool.get().await.expect("Failed to get a connection from the pool");
-    let sql = r#"
-SELECT entry_id, title, ts_rank_cd(search_vector, query) AS rank
-FROM entry, websearch_to_tsquery($1) query
-WHERE search_vector @@ query
+    let sql = format!(r#"
+SELECT e.entry_id, e.title, ts_rank_cd(e.search_vector, query) AS rank
+FROM entry e, websearch_to_tsquery($1) query
+WHERE e.search_vector @@ query
+AND {AGENT_FILTER}
+AND {SUBJECT_FILTER}
 ORDER BY rank DESC
 LIMIT 10;
-"#;
+"#);
     let query = match params.get("query") {
         Some(value) => value,
         None => "",
     };
-    let out = conn.query(sql, &[&query]).await.expect("Query should be valid")
+    // e.g. creator=12&role=translator for "translated by"
+    let creator: Option<i32> = params.get("creator").and_then(|id| id.parse().ok());
+    let role: Option<&str> = params.get("role").map(|r| r.as_str())
+        .filter(|r| AGENT_ROLES.contains(r));
+    let subject: Option<i32> = params.get("subject").and_then(|id| id.parse().ok());
+    let mut out: Vec<Entry> = conn.query(&sql, &[&query, &creator, &role, &subject]).await.expect("Query should be valid")
         .iter().map(|row|
                     Entry {
                         entry_id: row.get(0),
                         title: row.get(1),
                         rank: row.get(2),
+                        contributors: Vec::new(),
                     }).collect();
+
+    let contributors_sql = r#"
+SELECT ea.entry_id, ea.agent_role, a.full_name
+FROM entry_agent ea
+JOIN agent a ON a.agent_id = ea.agent_id
+WHERE ea.entry_id = ANY($1)
+ORDER BY a.full_name
+"#;
+    let entry_ids: Vec<i32> = out.iter().map(|e| e.entry_id).collect();
+    let contributors = conn.query(contributors_sql, &[&entry_ids]).await.expect("Query should be valid");
+    for entry in out.iter_mut() {
+        for agent_role in AGENT_ROLES {
+            let names: Vec<String> = contributors.iter()
+                .filter(|row| row.get::<_, i32>(0) == entry.entry_id && row.get::<_, &str>(1) == agent_role)
+                .map(|row| row.get(2))
+                .collect();
+            if !names.is_empty() {
+                entry.contributors.push(ContributorGroup {
+                    role: String::from(agent_role),
+                    names,
+                });
+            }
+        }
+    }
     tracing::debug!("{:?}", &out);
 
-    let lang_sql = r#"
+    let lang_sql = format!(r#"
 SELECT count(*) AS count,
        COALESCE(l.native_name, l.english_name, l.language_code) AS term,
        l.language_code AS id
@@ -81,37 +145,47 @@ FROM entry e
 JOIN entry_language el ON el.entry_id = e.entry_id
 JOIN known_language l ON l.language_code = el.language_code
 WHERE websearch_to_tsquery($1) @@ e.search_vector
+AND {AGENT_FILTER}
+AND {SUBJECT_FILTER}
 GROUP BY l.language_code, l.native_name, l.english_name
 ORDER BY count(*) DESC
-"#;
-    let langs = conn.query(lang_sql, &[&query]).await.expect("Query should be valid")
+"#);
+    let langs = conn.query(&lang_sql, &[&query, &creator, &role, &subject]).await.expect("Query should be valid")
         .iter().map(|row|
                     Facet {
                         count: row.get(0),
                         term: row.get(1),
                         id: row.get(2),
+                        role: None,
                     }).collect();
 
-    let authors_sql = r#"
+    // one value per agent and role, so the same person can be picked as
+    // author or as translator
+    let authors_sql = format!(r#"
 SELECT count(*) AS count,
        a.full_name AS term,
-       a.agent_id::TEXT AS id
+       a.agent_id::TEXT AS id,
+       ea.agent_role AS role
 FROM entry e
 JOIN entry_agent ea ON ea.entry_id = e.entry_id
 JOIN agent a ON a.agent_id = ea.agent_id
 WHERE websearch_to_tsquery($1) @@ e.search_vector
-GROUP BY a.full_name, a.agent_id
+AND {AGENT_FILTER}
+AND {SUBJECT_FILTER}
+AND ($3::TEXT IS NULL OR ea.agent_role = $3)
+GROUP BY a.full_name, a.agent_id, ea.agent_role
 ORDER BY count(*) DESC
-"#;
-    let authors = conn.query(authors_sql, &[&query]).await.expect("Query should be valid")
+"#);
+    let authors = conn.query(&authors_sql, &[&query, &creator, &role, &subject]).await.expect("Query should be valid")
         .iter().map(|row|
                     Facet {
                         count: row.get(0),
                         term: row.get(1),
                         id: row.get(2),
+                        role: Some(row.get(3)),
                     }).collect();
 
-    let libraries_sql = r#"
+    let libraries_sql = format!(r#"
 SELECT count(*) AS count,
        l.name AS term,
        l.library_id::TEXT AS id
@@ -120,20 +194,68 @@ JOIN datasource ds ON e.entry_id = ds.entry_id
 JOIN site s ON s.site_id = ds.site_id
 JOIN library l ON s.library_id = l.library_id
 WHERE websearch_to_tsquery($1) @@ e.search_vector
+AND {AGENT_FILTER}
+AND {SUBJECT_FILTER}
 GROUP BY l.name, l.library_id
 ORDER BY count(*) DESC
-"#;
+"#);
+
+    let libraries = conn.query(&libraries_sql, &[&query, &creator, &role, &subject]).await.expect("Query should be valid")
+        .iter().map(|row|
+                    Facet {
+                        count: row.get(0),
+                        term: row.get(1),
+                        id: row.get(2),
+                        role: None,
+                    }).collect();
 
-    let libraries = conn.query(libraries_sql, &[&query]).await.expect("Query should be valid")
+    let subjects_sql = format!(r#"
+SELECT count(*) AS count,
+       s.term AS term,
+       s.subject_id::TEXT AS id
+FROM entry e
+JOIN entry_subject es ON es.entry_id = e.entry_id
+JOIN subject s ON s.subject_id = es.subject_id
+WHERE websearch_to_tsquery($1) @@ e.search_vector
+AND {AGENT_FILTER}
+AND {SUBJECT_FILTER}
+GROUP BY s.term, s.subject_id
+ORDER BY count(*) DESC
+"#);
+    let subjects = conn.query(&subjects_sql, &[&query, &creator, &role, &subject]).await.expect("Query should be valid")
         .iter().map(|row|
                     Facet {
                         count: row.get(0),
                         term: row.get(1),
                         id: row.get(2),
+                        role: None,
                     }).collect();
 
 
 
+    // an entry can be both a book and an article in different libraries
+    let types_sql = format!(r#"
+SELECT count(DISTINCT e.entry_id) AS count,
+       ds.material_type AS term,
+       ds.material_type AS id
+FROM entry e
+JOIN datasource ds ON e.entry_id = ds.entry_id
+WHERE websearch_to_tsquery($1) @@ e.search_vector
+AND ds.material_type IS NOT NULL
+AND {AGENT_FILTER}
+AND {SUBJECT_FILTER}
+GROUP BY ds.material_type
+ORDER BY count(DISTINCT e.entry_id) DESC
+"#);
+    let types = conn.query(&types_sql, &[&query, &creator, &role, &subject]).await.expect("Query should be valid")
+        .iter().map(|row|
+                    Facet {
+                        count: row.get(0),
+                        term: row.get(1),
+                        id: row.get(2),
+                        role: None,
+                    }).collect();
+
     (StatusCode::OK, Json(SearchResult {
         entries: out,
         facets: FacetBlock {
@@ -149,10 +271,117 @@ ORDER BY count(*) DESC
                 name: String::from("libraryr"),
                 values: libraries,
             },
+            subject: FacetList {
+                name: String::from("subject"),
+                values: subjects,
+            },
+            material_type: FacetList {
+                name: String::from("type"),
+                values: types,
+            },
         }
     }))
 }
 
+#[derive(Serialize, Debug)]
+struct HarvestRun {
+    harvest_run_id: i32,
+    started: String,
+    finished: Option<String>,
+    status: String,
+    pages: i32,
+    records: i32,
+    inserted: i32,
+    updated: i32,
+    deleted: i32,
+    skipped: i32,
+    failed: i32,
+    full_texts: i32,
+    unchanged: i32,
+    error_message: Option<String>,
+    last_resumption_token: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct SiteRuns {
+    site_id: i32,
+    title: String,
+    url: String,
+    runs: Vec<HarvestRun>,
+}
+
+// The admin routes want the X-Admin-Token header to match ADMIN_TOKEN.
+// Without ADMIN_TOKEN they are off.
+fn is_admin(headers: &HeaderMap) -> bool {
+    let Ok(expected) = env::var("ADMIN_TOKEN") else {
+        return false;
+    };
+    let given = headers.get("x-admin-token").map_or(&b""[..], |value| value.as_bytes());
+    // same time whatever the mismatch
+    !expected.is_empty()
+        && given.len() == expected.len()
+        && given.iter().zip(expected.as_bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+// The last runs of every site (or of the site_id parameter), the most
+// recent first, so broken sources stand out. Error messages and resumption
+// tokens are not for the public.
+async fn harvest_runs(
+    State(pool): State<ConnectionPool>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<(StatusCode, Json<Vec<SiteRuns>>), StatusCode> {
+    if !is_admin(&headers) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let conn = pool.get().await.expect("Failed to get a connection from the pool");
+    let sql = r#"
+SELECT s.site_id, s.title, COALESCE(s.url, ''),
+       r.harvest_run_id, r.started::TEXT, r.finished::TEXT, r.status,
+       r.pages, r.records, r.inserted, r.updated, r.deleted, r.skipped, r.failed, r.full_texts,
+       r.unchanged, r.error_message, r.last_resumption_token
+FROM site s
+JOIN (SELECT hr.*, ROW_NUMBER() OVER (PARTITION BY hr.site_id ORDER BY hr.started DESC) AS position
+      FROM harvest_run hr) r ON r.site_id = s.site_id
+WHERE r.position <= $1
+AND ($2::INTEGER IS NULL OR s.site_id = $2)
+ORDER BY s.title, s.site_id, r.started DESC
+"#;
+    let limit: i64 = params.get("limit").and_then(|n| n.parse().ok()).unwrap_or(10).clamp(1, 100);
+    let site_id: Option<i32> = params.get("site_id").and_then(|id| id.parse().ok());
+    let mut out: Vec<SiteRuns> = Vec::new();
+    for row in conn.query(sql, &[&limit, &site_id]).await.expect("Query should be valid") {
+        let run = HarvestRun {
+            harvest_run_id: row.get(3),
+            started: row.get(4),
+            finished: row.get(5),
+            status: row.get(6),
+            pages: row.get(7),
+            records: row.get(8),
+            inserted: row.get(9),
+            updated: row.get(10),
+            deleted: row.get(11),
+            skipped: row.get(12),
+            failed: row.get(13),
+            full_texts: row.get(14),
+            unchanged: row.get(15),
+            error_message: row.get(16),
+            last_resumption_token: row.get(17),
+        };
+        let site_id: i32 = row.get(0);
+        match out.last_mut() {
+            Some(site) if site.site_id == site_id => site.runs.push(run),
+            _ => out.push(SiteRuns {
+                site_id,
+                title: row.get(1),
+                url: row.get(2),
+                runs: vec![run],
+            }),
+        }
+    }
+    Ok((StatusCode::OK, Json(out)))
+}
+
 #[tokio::main]
 async fn main() {
     // Setup database connection pool
@@ -176,6 +405,7 @@ async fn main() {
     // Create the axum router
     let app = Router::new()
         .route("/search", get(search))
+        .route("/admin/harvest-runs", get(harvest_runs))
         .with_state(pool);
 
     let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")